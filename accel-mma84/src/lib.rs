@@ -45,7 +45,7 @@ pub struct Accelerometer<'a> {
 
 impl<'a> Accelerometer<'a> {
     pub fn new<'b>(port: tessel::Port) -> Accelerometer<'b> {
-        let (i2c, gpio) = port.i2c();
+        let (i2c, gpio) = port.i2c().unwrap();
         let (i1, i2) = gpio.pin_select((5, 6));
 
         Accelerometer {
@@ -63,12 +63,12 @@ impl<'a> Accelerometer<'a> {
 
     /// Reads sequential buffers.
     fn read_registers(&mut self, cmd: Command, buf: &mut [u8]) -> io::Result<()> {
-        try!(self.i2c.transfer(I2C_ID, &[cmd as u8], buf));
+        try!(self.i2c.read_register(I2C_ID, cmd as u8, buf));
         Ok(())
     }
 
     fn write_register(&mut self, cmd: Command, value: u8) -> io::Result<()> {
-        self.i2c.send(I2C_ID, &[cmd as u8, value]);
+        try!(self.i2c.write_register(I2C_ID, cmd as u8, &[value]));
         Ok(())
     }
 
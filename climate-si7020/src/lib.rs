@@ -34,7 +34,7 @@ pub struct Climate<'a> {
 
 impl<'a> Climate<'a> {
     pub fn new<'b>(port: tessel::Port) -> Climate<'b> {
-        let (i2c, gpio) = port.i2c();
+        let (i2c, gpio) = port.i2c().unwrap();
         let (i1, i2) = gpio.pin_select((5, 6));
 
         Climate {
@@ -54,7 +54,7 @@ impl<'a> Climate<'a> {
     fn write(&mut self, values: &[Command], value: u8) -> io::Result<()> {
         let mut a: Vec<u8> = values.iter().map(|x| *x as u8).collect();
         a.push(value);
-        self.i2c.send(I2C_ID, &a);
+        try!(self.i2c.send(I2C_ID, &a));
         Ok(())
     }
 
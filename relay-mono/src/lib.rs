@@ -16,7 +16,7 @@ pub struct RelayArray<'a> {
 impl<'a> RelayArray<'a> {
     pub fn new<'b>(port: tessel::Port) -> RelayArray<'b> {
         //TODO don't use i2c
-        let (i2c, gpio) = port.i2c();
+        let (i2c, gpio) = port.i2c().unwrap();
         let (pin1, pin2) = gpio.pin_select((5, 6));
 
         //TODO do we need states or can we read pin output values?
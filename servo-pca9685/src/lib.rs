@@ -37,7 +37,7 @@ pub struct ServoArray<'a> {
 
 impl<'a> ServoArray<'a> {
     pub fn new<'b>(port: tessel::Port, addr2: bool, addr3: bool) -> ServoArray<'b> {
-        let (i2c, gpio) = port.i2c();
+        let (i2c, gpio) = port.i2c().unwrap();
         let (addr2, addr3, output_enable) = gpio.pin_select((5, 6, 7));
 
         ServoArray {
@@ -81,32 +81,36 @@ impl<'a> ServoArray<'a> {
         //    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid connection code."))
         //}
 
-        self.set_module_frequency(50);
+        try!(self.set_module_frequency(50));
 
         Ok(())
     }
 
-    pub fn set_module_frequency(&mut self, frequency: u64) {
+    pub fn set_module_frequency(&mut self, frequency: u64) -> io::Result<()> {
         let prescale: u8 = (((25000000 / (MAX as u64)) / frequency) - 1) as u8;
 
         let mut buf = [0; 1];
-        self.i2c.transfer(self.i2c_id, &[Command::MODE1 as u8], &mut buf);
+        try!(self.i2c.transfer(self.i2c_id, &[Command::MODE1 as u8], &mut buf));
         let mode = buf[0];
 
-        self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, mode | 0x10]);
-        self.i2c.send(self.i2c_id, &[Command::PRESCALE as u8, prescale]);
-        self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, mode]);
-        self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, 0xA1]);
+        try!(self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, mode | 0x10]));
+        try!(self.i2c.send(self.i2c_id, &[Command::PRESCALE as u8, prescale]));
+        try!(self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, mode]));
+        try!(self.i2c.send(self.i2c_id, &[Command::MODE1 as u8, 0xA1]));
+
+        Ok(())
     }
 
     /// Set duty cycle for entry 1 to 16.
-    pub fn set_duty_cycle(&mut self, i: usize, value: f64) {
+    pub fn set_duty_cycle(&mut self, i: usize, value: f64) -> io::Result<()> {
         let offset = ((i - 1) * 4) as u8;
         let reg = (((MAX - 1) as f64) * f64::max(f64::min(value, 1.0), 0.0)) as u16;
         println!("0 0 {:?} {:?}", (reg & 0xFF) as u8, ((reg >> 8) & 0xFF) as u8);
-        self.i2c.send(self.i2c_id, &[Command::LED0_ON_L as u8 + offset, 0]);
-        self.i2c.send(self.i2c_id, &[Command::LED0_ON_H as u8 + offset, 0]);
-        self.i2c.send(self.i2c_id, &[Command::LED0_OFF_L as u8 + offset, (reg & 0xFF) as u8]);
-        self.i2c.send(self.i2c_id, &[Command::LED0_OFF_H as u8 + offset, ((reg >> 8) & 0xFF) as u8]);
+        try!(self.i2c.send(self.i2c_id, &[Command::LED0_ON_L as u8 + offset, 0]));
+        try!(self.i2c.send(self.i2c_id, &[Command::LED0_ON_H as u8 + offset, 0]));
+        try!(self.i2c.send(self.i2c_id, &[Command::LED0_OFF_L as u8 + offset, (reg & 0xFF) as u8]));
+        try!(self.i2c.send(self.i2c_id, &[Command::LED0_OFF_H as u8 + offset, ((reg >> 8) & 0xFF) as u8]));
+
+        Ok(())
     }
 }
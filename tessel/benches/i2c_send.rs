@@ -0,0 +1,47 @@
+//! Compares writing the commands of a short I2C write one at a time against
+//! batching them into one write. Run with `cargo bench`.
+
+extern crate tessel;
+extern crate unix_socket;
+
+use std::io::Read;
+use std::thread;
+use std::time::Instant;
+use tessel::TesselError;
+use tessel::protocol::{Command, PortSocket};
+use unix_socket::UnixStream;
+
+const ITERATIONS: u32 = 20_000;
+
+// START, TX of a register address and value, and STOP, as I2cPort::send() writes them.
+fn send(socket: &mut PortSocket) -> Result<(), TesselError> {
+    try!(socket.write_command(Command::Start(0x1d << 1)));
+    try!(socket.write_command(Command::Tx(&[0x2A, 0x01])));
+    socket.write_command(Command::Stop)
+}
+
+fn time<F: FnMut(&mut PortSocket)>(name: &str, mut f: F) {
+    let (client, mut daemon) = UnixStream::pair().unwrap();
+    // Stand in for the port daemon, discarding everything written.
+    let reader = thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while daemon.read(&mut buffer).unwrap() > 0 {}
+    });
+
+    let mut socket = PortSocket::from_stream(client);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f(&mut socket);
+    }
+    let elapsed = start.elapsed();
+    drop(socket);
+    reader.join().unwrap();
+
+    let per_send = elapsed / ITERATIONS;
+    println!("{:>10}: {:?} per send ({:?} total)", name, per_send, elapsed);
+}
+
+fn main() {
+    time("separate", |socket| send(socket).unwrap());
+    time("batched", |socket| socket.batch(send).unwrap());
+}
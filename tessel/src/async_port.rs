@@ -0,0 +1,418 @@
+//! A non-blocking interface to a port for Tokio programs, enabled by the
+//! `tokio` feature.
+
+use protocol::{reply, Command};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use super::{I2cAddress, I2cPort, McuProfile, TesselError, GPIO_CFG_OPEN_DRAIN, I2C_PINS};
+use tokio::net::UnixStream;
+use tokio::sync::oneshot;
+use tokio::task;
+
+/// A connection to a port daemon that doesn't block the calling thread, so
+/// one async task can drive both ports and network I/O at once.
+///
+/// Commands are queued and written by a task spawned on the Tokio runtime,
+/// which also reads the replies and hands each to the request waiting on it.
+/// Requests can overlap; their replies are matched up in the order the
+/// requests were made. Asynchronous pin change and UART frames are skipped.
+///
+/// An `AsyncPort` has its own connection to the daemon, so it doesn't share
+/// pin locks or the peripheral claim with a `Port` for the same socket; use
+/// one or the other. There is no read timeout; wrap a request in
+/// `tokio::time::timeout()` to give up on it.
+#[derive(Debug)]
+pub struct AsyncPort {
+    shared: Arc<Mutex<Shared>>,
+}
+
+// State shared between an AsyncPort's handles and the task driving its socket.
+#[derive(Debug)]
+struct Shared {
+    // Encoded commands not yet written.
+    outgoing: VecDeque<u8>,
+    // Requests waiting on replies, in the order their commands were queued.
+    pending: VecDeque<Pending>,
+    // Wakes the driver when there is something new to write.
+    waker: Option<Waker>,
+    // Set once the socket failed; every request after that fails the same way.
+    error: Option<(io::ErrorKind, String)>,
+    // Live AsyncPort and AsyncI2c handles. The driver stops at zero, once
+    // everything queued has been written and answered.
+    handles: usize,
+    // Whether an AsyncI2c is alive.
+    i2c_enabled: bool,
+}
+
+// A request waiting on one reply per entry of `replies`.
+#[derive(Debug)]
+struct Pending {
+    // How many data bytes follow each reply if it is DATA.
+    replies: VecDeque<usize>,
+    response: Response,
+    sender: oneshot::Sender<Result<Response, TesselError>>,
+}
+
+// The replies a request got: each reply byte, and the data of its DATA
+// replies back to back.
+#[derive(Debug, Default)]
+struct Response {
+    headers: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl AsyncPort {
+    /// Connects to the port daemon's socket at `path`. Must be called from
+    /// within a Tokio runtime, which runs the task driving the socket.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<AsyncPort, TesselError> {
+        AsyncPort::from_std(try!(StdUnixStream::connect(path)))
+    }
+
+    /// Like `connect()`, over a socket that is already connected, e.g. one
+    /// end of a `UnixStream::pair()` in tests.
+    pub fn from_std(stream: StdUnixStream) -> Result<AsyncPort, TesselError> {
+        try!(stream.set_nonblocking(true));
+        let stream = try!(UnixStream::from_std(stream));
+        let shared = Arc::new(Mutex::new(Shared {
+            outgoing: VecDeque::new(),
+            pending: VecDeque::new(),
+            waker: None,
+            error: None,
+            handles: 1,
+            i2c_enabled: false,
+        }));
+        task::spawn(Driver { stream: stream, shared: shared.clone(), incoming: vec![] });
+        Ok(AsyncPort { shared: shared })
+    }
+
+    /// Enables I2C at `frequency` Hz, like `Port::i2c_with_config()`. Fails
+    /// with `PortBusy` if an `AsyncI2c` from this port is still alive.
+    pub fn i2c(&self, frequency: u32) -> Result<AsyncI2c, TesselError> {
+        let mut commands = vec![];
+        // SCL and SDA may have been left push-pull by earlier GPIO use.
+        for &pin in I2C_PINS.iter() {
+            commands.extend(Command::GpioCfg { pin: pin as u8, mode: GPIO_CFG_OPEN_DRAIN }.encode());
+        }
+        let baud = I2cPort::compute_baud(&McuProfile::tessel2(), frequency);
+        commands.extend(Command::EnableI2c { baud: baud }.encode());
+
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.i2c_enabled {
+                return Err(TesselError::PortBusy);
+            }
+            shared.i2c_enabled = true;
+        }
+        drop(self.request(commands, VecDeque::new()));
+        Ok(AsyncI2c { port: self.clone() })
+    }
+
+    // Queues `commands`, expecting one reply per entry of `replies`, and
+    // returns where the replies will be sent.
+    fn request(&self, commands: Vec<u8>, replies: VecDeque<usize>) -> oneshot::Receiver<Result<Response, TesselError>> {
+        let (sender, receiver) = oneshot::channel();
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(err) = Shared::error(&shared) {
+            let _ = sender.send(Err(err));
+            return receiver;
+        }
+
+        shared.outgoing.extend(commands);
+        if replies.is_empty() {
+            let _ = sender.send(Ok(Response::default()));
+        } else {
+            shared.pending.push_back(Pending { replies: replies, response: Response::default(), sender: sender });
+        }
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        receiver
+    }
+}
+
+impl Clone for AsyncPort {
+    fn clone(&self) -> AsyncPort {
+        self.shared.lock().unwrap().handles += 1;
+        AsyncPort { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for AsyncPort {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.handles -= 1;
+        // Let the driver notice it can stop.
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Shared {
+    // The error the socket failed with, if it did.
+    fn error(shared: &MutexGuard<Shared>) -> Option<TesselError> {
+        shared.error.as_ref().map(|&(kind, ref msg)| TesselError::Io(io::Error::new(kind, msg.clone())))
+    }
+
+    // Fails every waiting request with `err`, and every later one too.
+    fn fail(shared: &mut MutexGuard<Shared>, err: io::Error) {
+        shared.error = Some((err.kind(), err.to_string()));
+        shared.outgoing.clear();
+        for pending in shared.pending.drain(..) {
+            let err = TesselError::Io(io::Error::new(err.kind(), err.to_string()));
+            let _ = pending.sender.send(Err(err));
+        }
+    }
+
+    // Hands the complete frames at the start of `incoming` to the requests
+    // waiting on them, leaving any partial frame for the next read.
+    fn dispatch(shared: &mut MutexGuard<Shared>, incoming: &mut Vec<u8>) {
+        let mut used = 0;
+        while used < incoming.len() {
+            let frame = &incoming[used..];
+            let header = frame[0];
+            if header >= reply::MIN_ASYNC.0 {
+                // Skip the frame; only UART data carries a body.
+                if header == reply::ASYNC_UART_RX.0 {
+                    if frame.len() < 2 || frame.len() < 2 + frame[1] as usize {
+                        break;
+                    }
+                    used += 2 + frame[1] as usize;
+                } else {
+                    used += 1;
+                }
+                continue;
+            }
+            if !(reply::ACK.0..=reply::PONG.0).contains(&header) || shared.pending.is_empty() {
+                // Not a reply, or one nothing is waiting on.
+                used += 1;
+                continue;
+            }
+
+            let len = if header == reply::DATA.0 {
+                shared.pending[0].replies[0]
+            } else {
+                0
+            };
+            if frame.len() < 1 + len {
+                break;
+            }
+            used += 1 + len;
+
+            let done = {
+                let pending = &mut shared.pending[0];
+                pending.replies.pop_front();
+                pending.response.headers.push(header);
+                pending.response.data.extend_from_slice(&frame[1..1 + len]);
+                pending.replies.is_empty()
+            };
+            if done {
+                let pending = shared.pending.pop_front().unwrap();
+                let _ = pending.sender.send(Ok(pending.response));
+            }
+        }
+        incoming.drain(..used);
+    }
+}
+
+// The task that writes queued commands and dispatches replies.
+struct Driver {
+    stream: UnixStream,
+    shared: Arc<Mutex<Shared>>,
+    // Bytes read that don't make up a whole frame yet.
+    incoming: Vec<u8>,
+}
+
+impl Driver {
+    // Writes and reads whatever the socket is ready for. Returns whether the
+    // driver is done, because every handle is gone and nothing is left to
+    // write or wait on.
+    fn poll_socket(&mut self, shared: &mut MutexGuard<Shared>, cx: &mut Context) -> io::Result<bool> {
+        while !shared.outgoing.is_empty() {
+            let result = self.stream.try_write(shared.outgoing.as_slices().0);
+            match result {
+                Ok(written) => {
+                    shared.outgoing.drain(..written);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    match self.stream.poll_write_ready(cx) {
+                        Poll::Ready(result) => try!(result),
+                        Poll::Pending => break,
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut buffer = [0; 256];
+        loop {
+            match self.stream.try_read(&mut buffer) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Port daemon closed the socket.")),
+                Ok(read) => self.incoming.extend_from_slice(&buffer[..read]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    match self.stream.poll_read_ready(cx) {
+                        Poll::Ready(result) => try!(result),
+                        Poll::Pending => break,
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Shared::dispatch(shared, &mut self.incoming);
+
+        Ok(shared.handles == 0 && shared.outgoing.is_empty() && shared.pending.is_empty())
+    }
+}
+
+impl Future for Driver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let driver = &mut *self;
+        let handle = driver.shared.clone();
+        let mut shared = handle.lock().unwrap();
+        shared.waker = Some(cx.waker().clone());
+        match driver.poll_socket(&mut shared, cx) {
+            Ok(false) => Poll::Pending,
+            Ok(true) => Poll::Ready(()),
+            Err(err) => {
+                Shared::fail(&mut shared, err);
+                Poll::Ready(())
+            }
+        }
+    }
+}
+
+/// I2C on an `AsyncPort`, created by `AsyncPort::i2c()`. Disables I2C when
+/// dropped.
+///
+/// Each method queues its transaction right away and returns a `Reply`
+/// to `.await`, so transactions go out in the order the methods are called.
+#[derive(Debug)]
+pub struct AsyncI2c {
+    port: AsyncPort,
+}
+
+impl AsyncI2c {
+    /// Writes `write_buf` to the slave at `address`, like `I2cPort::send()`.
+    /// Resolves to `Nack` if the slave didn't acknowledge.
+    pub fn send<A: Into<I2cAddress>>(&self, address: A, write_buf: &[u8]) -> Reply<()> {
+        let address = match address.into().validate() {
+            Ok(address) => address,
+            Err(err) => return Reply::failed(err),
+        };
+        let mut commands = AsyncI2c::start(address, false);
+        commands.extend(Command::Tx(write_buf).encode());
+        commands.extend(Command::Stop.encode());
+
+        Reply {
+            receiver: self.port.request(commands, vec![0].into_iter().collect()),
+            parse: AsyncI2c::parse_ack,
+        }
+    }
+
+    /// Reads `len` bytes from the slave at `address`, like `I2cPort::read()`.
+    pub fn read<A: Into<I2cAddress>>(&self, address: A, len: usize) -> Reply<Vec<u8>> {
+        let address = match address.into().validate() {
+            Ok(address) => address,
+            Err(err) => return Reply::failed(err),
+        };
+        let mut commands = AsyncI2c::start(address, true);
+        // One RX of 255 bytes at most per DATA reply.
+        let mut replies = VecDeque::new();
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(u8::max_value() as usize);
+            commands.extend(Command::Rx(chunk as u8).encode());
+            replies.push_back(chunk);
+            remaining -= chunk;
+        }
+        commands.extend(Command::Stop.encode());
+
+        Reply {
+            receiver: self.port.request(commands, replies),
+            parse: AsyncI2c::parse_data,
+        }
+    }
+
+    // Encodes the START that addresses the slave, the same way
+    // I2cPort::start() does.
+    fn start(address: I2cAddress, read: bool) -> Vec<u8> {
+        match address {
+            I2cAddress::SevenBit(address) | I2cAddress::Reserved(address) => {
+                Command::Start(address << 1 | read as u8).encode()
+            }
+            I2cAddress::TenBit(address) => {
+                let header = 0b1111_0000 | ((address >> 7) as u8 & 0b110);
+                let mut commands = Command::Start(header).encode();
+                commands.extend(Command::Tx(&[address as u8]).encode());
+                if read {
+                    commands.extend(Command::Start(header | 1).encode());
+                }
+                commands
+            }
+        }
+    }
+
+    fn parse_ack(response: Response) -> Result<(), TesselError> {
+        match response.headers[0] {
+            x if x == reply::ACK.0 => Ok(()),
+            x if x == reply::NACK.0 => Err(TesselError::Nack),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    fn parse_data(response: Response) -> Result<Vec<u8>, TesselError> {
+        if response.headers.iter().any(|&header| header != reply::DATA.0) {
+            return Err(TesselError::InvalidReply);
+        }
+        Ok(response.data)
+    }
+}
+
+impl Drop for AsyncI2c {
+    fn drop(&mut self) {
+        drop(self.port.request(Command::DisableI2c.encode(), VecDeque::new()));
+        self.port.shared.lock().unwrap().i2c_enabled = false;
+    }
+}
+
+/// The outcome of a request on an `AsyncPort`, resolving once the
+/// coprocessor has answered. Requests are sent whether or not their `Reply`
+/// is awaited.
+#[derive(Debug)]
+pub struct Reply<T> {
+    receiver: oneshot::Receiver<Result<Response, TesselError>>,
+    parse: fn(Response) -> Result<T, TesselError>,
+}
+
+impl<T> Reply<T> {
+    // A reply that resolves to `err` without sending anything.
+    fn failed(err: TesselError) -> Reply<T> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = sender.send(Err(err));
+        Reply { receiver: receiver, parse: |_| Err(TesselError::InvalidReply) }
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, TesselError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, TesselError>> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(Ok(response))) => Poll::Ready((self.parse)(response)),
+            Poll::Ready(Ok(Err(err))) => Poll::Ready(Err(err)),
+            // The driver stopped without answering, e.g. the runtime shut down.
+            Poll::Ready(Err(_)) => {
+                Poll::Ready(Err(TesselError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "Port connection closed."))))
+            }
+        }
+    }
+}
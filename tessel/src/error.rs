@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The ways talking to Tessel hardware can fail.
+///
+/// Converts to and from `io::Error`, so code that works in terms of
+/// `io::Result` can keep using `try!` on this crate's methods.
+#[derive(Debug)]
+pub enum TesselError {
+    /// The pin is already held by another `Pin`.
+    PinBusy,
+    /// Another peripheral is already enabled on the port.
+    PortBusy,
+    /// The pin doesn't exist, or doesn't support what was asked of it.
+    UnsupportedPin,
+    /// An argument was out of range, such as an I2C address or a frequency.
+    InvalidArgument(&'static str),
+    /// The coprocessor sent a reply that doesn't fit the protocol.
+    InvalidReply,
+    /// The coprocessor didn't answer in time.
+    TimedOut,
+    /// No I2C slave acknowledged the address.
+    Nack,
+    /// Reading or writing the port socket or a sysfs file failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for TesselError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TesselError::PinBusy => write!(f, "Pin is in use."),
+            TesselError::PortBusy => write!(f, "Port is in use by another peripheral."),
+            TesselError::UnsupportedPin => write!(f, "Pin does not exist or does not support this function."),
+            TesselError::InvalidArgument(msg) => write!(f, "{}", msg),
+            TesselError::InvalidReply => write!(f, "Unexpected reply from the coprocessor."),
+            TesselError::TimedOut => write!(f, "Timed out waiting for the coprocessor."),
+            TesselError::Nack => write!(f, "I2C slave did not acknowledge."),
+            TesselError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for TesselError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            TesselError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TesselError {
+    fn from(err: io::Error) -> TesselError {
+        TesselError::Io(err)
+    }
+}
+
+impl From<TesselError> for io::Error {
+    fn from(err: TesselError) -> io::Error {
+        let kind = match err {
+            TesselError::Io(err) => return err,
+            TesselError::PinBusy | TesselError::PortBusy => io::ErrorKind::WouldBlock,
+            TesselError::UnsupportedPin | TesselError::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+            TesselError::InvalidReply => io::ErrorKind::InvalidData,
+            TesselError::TimedOut => io::ErrorKind::TimedOut,
+            TesselError::Nack => io::ErrorKind::NotFound,
+        };
+        io::Error::new(kind, err)
+    }
+}
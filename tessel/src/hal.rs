@@ -0,0 +1,67 @@
+//! `embedded-hal` trait implementations, enabled by the `embedded-hal` feature.
+
+use embedded_hal::blocking::i2c;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use super::{I2cOp, I2cPort, Pin, TesselError};
+
+impl<'a> i2c::Read for I2cPort<'a> {
+    type Error = TesselError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), TesselError> {
+        I2cPort::read(self, address, buffer)
+    }
+}
+
+impl<'a> i2c::Write for I2cPort<'a> {
+    type Error = TesselError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), TesselError> {
+        self.send(address, bytes)
+    }
+}
+
+impl<'a> i2c::WriteRead for I2cPort<'a> {
+    type Error = TesselError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), TesselError> {
+        self.transfer(address, bytes, buffer)
+    }
+}
+
+impl<'a> i2c::Transactional for I2cPort<'a> {
+    type Error = TesselError;
+
+    fn exec<'b>(&mut self, address: u8, operations: &mut [i2c::Operation<'b>]) -> Result<(), TesselError> {
+        let mut ops: Vec<I2cOp> = operations.iter_mut()
+            .map(|op| match *op {
+                i2c::Operation::Write(bytes) => I2cOp::Write(bytes),
+                i2c::Operation::Read(ref mut buffer) => I2cOp::Read(buffer),
+            })
+            .collect();
+        self.transaction(address, &mut ops)
+    }
+}
+
+impl<'a> InputPin for Pin<'a> {
+    type Error = TesselError;
+
+    fn is_high(&self) -> Result<bool, TesselError> {
+        self.read()
+    }
+
+    fn is_low(&self) -> Result<bool, TesselError> {
+        self.read().map(|high| !high)
+    }
+}
+
+impl<'a> OutputPin for Pin<'a> {
+    type Error = TesselError;
+
+    fn set_low(&mut self) -> Result<(), TesselError> {
+        self.write(false)
+    }
+
+    fn set_high(&mut self) -> Result<(), TesselError> {
+        self.write(true)
+    }
+}
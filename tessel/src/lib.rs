@@ -4,18 +4,35 @@
 extern crate atomic_option;
 extern crate unix_socket;
 extern crate bit_set;
+#[cfg(feature = "embedded-hal")] extern crate embedded_hal;
+#[cfg(feature = "log")] #[macro_use] extern crate log;
+#[cfg(feature = "serde")] #[macro_use] extern crate serde;
+#[cfg(feature = "tokio")] extern crate tokio;
 
 pub mod protocol;
+mod error;
+#[cfg(feature = "embedded-hal")] mod hal;
+#[cfg(feature = "tokio")] mod async_port;
+
+pub use error::TesselError;
+#[cfg(feature = "tokio")] pub use async_port::{AsyncI2c, AsyncPort, Reply};
 
 use atomic_option::AtomicOption;
 use protocol::{Command, reply, PortSocket};
-use std::fs::File;
+use std::cell::Cell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::marker::PhantomData;
-use std::sync::atomic::Ordering;
+use std::ops::{BitOr, Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use bit_set::BitSet;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 // TODO Corking reduces latency, as spid adds overhead for each packet
 
@@ -24,11 +41,57 @@ use std::sync::{Arc, Mutex, MutexGuard};
 const PORT_A_UDS_PATH: &'static str = "/var/run/tessel/port_a";
 const PORT_B_UDS_PATH: &'static str = "/var/run/tessel/port_b";
 
-const MCU_MAX_SPEED: u32 = 48e6 as u32;
-// TODO: Replace with better name
-const MCU_MAX_SCL_RISE_TIME_NS: f64 = 1.5e-8 as f64;
-const MCU_MAGIC_DIV_FACTOR_FOR_I2C_BAUD: u8 = 2;
-const MCU_MAGIC_SUBTRACT_FACTOR_FOR_I2C_BAUD: u8 = 5;
+// Path to the sysfs file exposing the state of the board button.
+const BUTTON_PATH: &'static str = "/sys/devices/platform/gpio-keys/button/value";
+// How often to sample the button while waiting for a press.
+const BUTTON_POLL_INTERVAL_MS: u64 = 10;
+
+// Files holding the board's MAC addresses, in order of preference: the
+// Ethernet address, then the WiFi one.
+const DEVICE_ID_PATHS: [&'static str; 2] = ["/sys/class/net/eth0/address", "/sys/class/net/wlan0/address"];
+
+// Dots and dashes for the characters LED::morse() can send.
+const MORSE_CODE: [(char, &'static str); 36] = [
+    ('A', ".-"), ('B', "-..."), ('C', "-.-."), ('D', "-.."), ('E', "."), ('F', "..-."),
+    ('G', "--."), ('H', "...."), ('I', ".."), ('J', ".---"), ('K', "-.-"), ('L', ".-.."),
+    ('M', "--"), ('N', "-."), ('O', "---"), ('P', ".--."), ('Q', "--.-"), ('R', ".-."),
+    ('S', "..."), ('T', "-"), ('U', "..-"), ('V', "...-"), ('W', ".--"), ('X', "-..-"),
+    ('Y', "-.--"), ('Z', "--.."), ('0', "-----"), ('1', ".----"), ('2', "..---"),
+    ('3', "...--"), ('4', "....-"), ('5', "....."), ('6', "-...."), ('7', "--..."),
+    ('8', "---.."), ('9', "----."),
+];
+
+// How long Port::ping() waits for an answer if the port has no read timeout.
+const PING_TIMEOUT_MS: u64 = 1000;
+
+// Number of GPIO pins exposed on each module port.
+const PORT_PIN_COUNT: usize = 8;
+
+// Pins that can output PWM, and the timer/counter that drives them.
+const PWM_PINS: [usize; 2] = [5, 6];
+const PWM_TCC_ID: u8 = 0;
+// Servos expect a pulse every 20ms, 1ms wide at 0 degrees and 2ms at 180.
+const SERVO_FREQUENCY: u32 = 50;
+const SERVO_DEFAULT_RANGE_US: (u32, u32) = (1000, 2000);
+// Clock prescalers available to the PWM timer, indexed by register value.
+const PWM_PRESCALERS: [u32; 8] = [1, 2, 4, 8, 16, 64, 256, 1024];
+// Pins that can read analog values, their ADC resolution, and the reference voltage.
+const ANALOG_PINS: [usize; 4] = [4, 5, 6, 7];
+const ANALOG_RESOLUTION: u16 = 4096;
+const ANALOG_REFERENCE_VOLTAGE: f32 = 3.3;
+// Pins with internal pull resistors.
+const PULL_PINS: [usize; 6] = [2, 3, 4, 5, 6, 7];
+// Pins that can report edges to `Pin::wait_for_edge`.
+const INTERRUPT_PINS: [usize; 4] = [2, 5, 6, 7];
+
+// I2C pins are SCL on 0 and SDA on 1.
+const I2C_PINS: [usize; 2] = [0, 1];
+// SPI pins are SCK on 2, MISO on 3, and MOSI on 4.
+const SPI_PINS: [usize; 3] = [2, 3, 4];
+// UART pins are TX on 5 and RX on 6.
+const UART_PINS: [usize; 2] = [5, 6];
+// GPIO_CFG drive mode for open-drain outputs, as the I2C bus needs.
+const GPIO_CFG_OPEN_DRAIN: u8 = 1;
 
 /// Primary exported Tessel object with access to module ports, LEDs, and a button.
 /// # Example
@@ -42,385 +105,5217 @@ const MCU_MAGIC_SUBTRACT_FACTOR_FOR_I2C_BAUD: u8 = 5;
 /// assert_eq!(t.led.len(), 4);
 /// # }
 /// ```
+#[derive(Debug)]
 pub struct Tessel {
     // An array of LED structs.
     pub led: Vec<LED>,
+    // The button on the board.
+    pub button: Button,
+    // Port A, if it was enabled with TesselBuilder. Otherwise use ports().
+    pub port_a: Option<Port>,
+    // Port B, if it was enabled with TesselBuilder. Otherwise use ports().
+    pub port_b: Option<Port>,
 }
 
 lazy_static! {
     // Create a tuple with two ports, one on each domain socket path.
-    static ref TESSEL_PORTS: AtomicOption<(Port, Port)> = AtomicOption::new(Box::new((
-        Port::new(PORT_A_UDS_PATH),
-        Port::new(PORT_B_UDS_PATH),
-    )));
+    // Left empty if either socket can't be connected to.
+    static ref TESSEL_PORTS: AtomicOption<(Port, Port)> = {
+        match (Port::try_new(PORT_A_UDS_PATH), Port::try_new(PORT_B_UDS_PATH)) {
+            (Ok(a), Ok(b)) => AtomicOption::new(Box::new((a, b))),
+            _ => AtomicOption::empty(),
+        }
+    };
+
+    // Clones of the ports ports() handed out, so Tessel::shutdown() can
+    // disable their peripherals.
+    static ref ISSUED_PORTS: Mutex<Option<(Port, Port)>> = Mutex::new(None);
 }
 
 impl Tessel {
     // new() returns a Tessel struct conforming to the Tessel 2's functionality.
     pub fn new() -> Tessel {
-        // Create models for the four LEDs.
-        let red_led = LED::new("red", "error");
-        let amber_led = LED::new("amber", "wlan");
-        let green_led = LED::new("green", "user1");
-        let blue_led = LED::new("blue", "user2");
+        Tessel::try_new().expect("Could not initialize Tessel.")
+    }
 
-        // Return the Tessel with these fields.
-        Tessel {
-            led: vec![red_led, amber_led, green_led, blue_led],
-        }
+    // try_new() is like new(), but returns an error if the LEDs can't be opened.
+    pub fn try_new() -> Result<Tessel, TesselError> {
+        TesselBuilder::new().build()
     }
 
-    pub fn ports() -> Option<(Port, Port)> {
-        TESSEL_PORTS.take(Ordering::Relaxed).map(|x| *x)
+    // with_paths() is like try_new(), but also connects port_a and port_b to
+    // the daemon sockets at the given paths, e.g. a mock daemon in tests.
+    pub fn with_paths<P: AsRef<Path>, Q: AsRef<Path>>(a_path: P, b_path: Q) -> Result<Tessel, TesselError> {
+        TesselBuilder::new()
+            .port_a_path(a_path)
+            .port_b_path(b_path)
+            .enable_port_a()
+            .enable_port_b()
+            .build()
     }
-}
 
-/// A Port is a model of the Tessel hardware ports.
-/// # Example
-/// ```
-/// use tessel::Port;
-/// ```
-pub struct Port {
-    socket: Arc<Mutex<PortSocket>>,
-}
+    // Returns the LED of the given color ("red", "amber", "green", or "blue").
+    pub fn led_by_color(&mut self, color: &str) -> Option<&mut LED> {
+        self.led.iter_mut().find(|led| led.color == color)
+    }
 
-impl Port {
-    pub fn new(path: &str) -> Port {
-        // Create and return the port struct
-        Port {
-            socket: Arc::new(Mutex::new(PortSocket::new(path))),
+    // Returns the LED with the given role ("error", "wlan", "user1", or "user2").
+    pub fn led_by_kind(&mut self, kind: &str) -> Option<&mut LED> {
+        self.led.iter_mut().find(|led| led.kind == kind)
+    }
+
+    // Turns the LEDs at the given indices on or off, back to back. Every index
+    // is checked first, so an invalid one leaves all of the LEDs unchanged.
+    pub fn set_leds(&mut self, states: &[(usize, bool)]) -> Result<(), TesselError> {
+        if states.iter().any(|&(index, _)| index >= self.led.len()) {
+            return Err(TesselError::InvalidArgument("LED index out of range."));
+        }
+        for &(index, on) in states {
+            if on {
+                try!(self.led[index].on());
+            } else {
+                try!(self.led[index].off());
+            }
         }
+        Ok(())
     }
 
-    pub fn pins(&mut self) -> (Pin, Pin, Pin) {
-        (
-            Pin::new(5, self.socket.clone()),
-            Pin::new(6, self.socket.clone()),
-            Pin::new(7, self.socket.clone()),
-        )
+    // Turns every LED off, e.g. when shutting down.
+    pub fn all_leds_off(&mut self) -> Result<(), TesselError> {
+        for led in self.led.iter_mut() {
+            try!(led.off());
+        }
+        Ok(())
     }
 
-    pub fn i2c<'b>(self) -> (I2cPort<'b>, Gpio<'b>) {
-        let mut available = BitSet::new();
-        for i in 2..8 {
-            available.insert(i);
+    // Turns every LED off and disables the peripheral enabled on each port,
+    // both the Tessel's own and the ones ports() handed out, for a clean
+    // exit. Keeps going after an error, and returns the first one.
+    pub fn shutdown(&mut self) -> Result<(), TesselError> {
+        let mut result = Ok(());
+        for led in self.led.iter_mut() {
+            result = result.and(led.off());
         }
-        (I2cPort::new(self.socket.clone()), Gpio::new(self.socket.clone(), available))
+        let issued = ISSUED_PORTS.lock().unwrap();
+        let mut ports: Vec<&Port> = self.port_a.iter().chain(self.port_b.iter()).collect();
+        if let Some((ref a, ref b)) = *issued {
+            ports.push(a);
+            ports.push(b);
+        }
+        for port in ports {
+            result = result.and(port.disable_peripheral());
+        }
+        result
     }
-}
 
-/// Gpio is a selection of pins.
-#[allow(dead_code)]
-pub struct Gpio<'a> {
-    socket: Arc<Mutex<PortSocket>>,
-    available: BitSet,
-    _phantom: PhantomData<&'a Port>,
-}
+    // Iterates over all of the LEDs.
+    pub fn leds(&mut self) -> impl Iterator<Item = &mut LED> {
+        self.led.iter_mut()
+    }
 
-impl<'a> Gpio<'a> {
-    pub fn new<'b>(socket: Arc<Mutex<PortSocket>>, available: BitSet) -> Gpio<'b> {
-        // Create and return the port struct
-        Gpio {
-            socket: socket,
-            available: available,
-            _phantom: PhantomData,
-        }
+    // Returns accessors for the LEDs by the role they are labeled with.
+    pub fn named_leds<'a>(&'a mut self) -> NamedLeds<'a> {
+        NamedLeds { leds: &mut self.led }
     }
 
-    // TODO return iterator
-    //pub fn pins() { }
+    // Returns a stable identifier for the board, its MAC address (e.g.
+    // "02:a3:1f:00:4c:10"), for provisioning and fleet management.
+    pub fn device_id(&self) -> Result<String, TesselError> {
+        Tessel::read_device_id(&DEVICE_ID_PATHS)
+    }
 
-    pub fn pin_select<H: PinSelect<'a>>(self, select: H) -> H::Output {
-        select.select(self.socket.clone())
+    // Reads the first of `paths` that exists and holds an address.
+    fn read_device_id<P: AsRef<Path>>(paths: &[P]) -> Result<String, TesselError> {
+        for path in paths {
+            let mut buf = String::new();
+            match File::open(path).and_then(|mut file| file.read_to_string(&mut buf)) {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(TesselError::Io(err)),
+            }
+            let id = buf.trim();
+            if !id.is_empty() {
+                return Ok(id.to_lowercase());
+            }
+        }
+        Err(TesselError::Io(io::Error::new(io::ErrorKind::NotFound, "No device identifier found.")))
+    }
+
+    // ports() returns both module ports the first time it is called. Returns
+    // None after that, or if either port's socket could not be connected to.
+    pub fn ports() -> Option<(Port, Port)> {
+        let ports = TESSEL_PORTS.take(Ordering::Relaxed).map(|x| *x);
+        if let Some(ref ports) = ports {
+            *ISSUED_PORTS.lock().unwrap() = Some(ports.clone());
+        }
+        ports
     }
 }
 
-/// Pin tuple conversion for gpio:pins(..)
-pub trait PinSelect<'a> {
-    type Output;
-    fn validate(&self, &BitSet<usize>) -> bool;
-    fn select(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output;
+/// Builds a `Tessel` with only the parts a program needs.
+///
+/// LEDs are opened by default and ports are not, so a program that only uses
+/// port A doesn't fail when port B's daemon is down. Ports enabled here are
+/// separate connections from the ones returned by `Tessel::ports()`.
+/// # Example
+/// ```rust,no_run
+/// use tessel::TesselBuilder;
+///
+/// let mut t = TesselBuilder::new().enable_port_a().leds(false).build().unwrap();
+/// let (i2c, _) = t.port_a.take().unwrap().i2c().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct TesselBuilder {
+    port_a: bool,
+    port_a_path: PathBuf,
+    port_b: bool,
+    port_b_path: PathBuf,
+    leds: bool,
 }
 
-impl<'a> PinSelect<'a> for usize {
-    type Output = Pin<'a>;
-    fn validate(&self, set: &BitSet<usize>) -> bool {
-        set.contains(*self)
-    }
-    fn select<'b>(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output {
-        Pin::new(*self, socket)
+impl Default for TesselBuilder {
+    fn default() -> TesselBuilder {
+        TesselBuilder {
+            port_a: false,
+            port_a_path: PathBuf::from(PORT_A_UDS_PATH),
+            port_b: false,
+            port_b_path: PathBuf::from(PORT_B_UDS_PATH),
+            leds: true,
+        }
     }
 }
 
-impl<'a> PinSelect<'a> for (usize, usize) {
-    type Output = (Pin<'a>, Pin<'a>);
-    fn validate(&self, set: &BitSet<usize>) -> bool {
-        set.contains(self.0) || set.contains(self.1)
+impl TesselBuilder {
+    pub fn new() -> TesselBuilder {
+        TesselBuilder::default()
     }
-    fn select<'b>(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output {
-        (Pin::new(self.0, socket.clone()), Pin::new(self.1, socket))
+
+    /// Connects to port A when building.
+    pub fn enable_port_a(mut self) -> TesselBuilder {
+        self.port_a = true;
+        self
     }
-}
 
-impl<'a> PinSelect<'a> for (usize, usize, usize) {
-    type Output = (Pin<'a>, Pin<'a>, Pin<'a>);
-    fn validate(&self, set: &BitSet<usize>) -> bool {
-        set.contains(self.0) || set.contains(self.1) || set.contains(self.2)
+    /// Connects to port B when building.
+    pub fn enable_port_b(mut self) -> TesselBuilder {
+        self.port_b = true;
+        self
     }
-    fn select<'b>(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output {
-        (Pin::new(self.0, socket.clone()), Pin::new(self.1, socket.clone()), Pin::new(self.2, socket))
+
+    /// Sets where port A's daemon socket is, if not `/var/run/tessel/port_a`.
+    pub fn port_a_path<P: AsRef<Path>>(mut self, path: P) -> TesselBuilder {
+        self.port_a_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Sets where port B's daemon socket is, if not `/var/run/tessel/port_b`.
+    pub fn port_b_path<P: AsRef<Path>>(mut self, path: P) -> TesselBuilder {
+        self.port_b_path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Sets whether to open the LEDs. Without them, `Tessel::led` is empty.
+    pub fn leds(mut self, leds: bool) -> TesselBuilder {
+        self.leds = leds;
+        self
+    }
+
+    /// Opens everything that was asked for, failing if any of it can't be.
+    pub fn build(self) -> Result<Tessel, TesselError> {
+        let mut leds = vec![];
+        if self.leds {
+            // Create models for the four LEDs.
+            leds.push(try!(LED::try_new("red", "error")));
+            leds.push(try!(LED::try_new("amber", "wlan")));
+            leds.push(try!(LED::try_new("green", "user1")));
+            leds.push(try!(LED::try_new("blue", "user2")));
+        }
+
+        let port_a = if self.port_a { Some(try!(Port::try_new(&self.port_a_path))) } else { None };
+        let port_b = if self.port_b { Some(try!(Port::try_new(&self.port_b_path))) } else { None };
+
+        Ok(Tessel {
+            led: leds,
+            button: Button::new(BUTTON_PATH),
+            port_a: port_a,
+            port_b: port_b,
+        })
     }
 }
 
-/// A GPIO pin usable as an input or output.
-pub struct Pin<'a> {
-    index: usize,
-    socket: Arc<Mutex<PortSocket>>,
-    _phantom: PhantomData<&'a Port>,
+/// The Tessel's LEDs, by the role they are labeled with on the board. Each
+/// accessor returns `None` if the `Tessel` was built without that LED, e.g.
+/// with `TesselBuilder::leds(false)`.
+/// # Example
+/// ```rust,no_run
+/// use tessel::Tessel;
+///
+/// let mut t = Tessel::new();
+/// t.named_leds().user1().unwrap().on().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct NamedLeds<'a> {
+    leds: &'a mut [LED],
 }
 
-impl<'a> Pin<'a> {
-    fn new<'b>(index: usize, socket: Arc<Mutex<PortSocket>>) -> Pin<'b> {
-        Pin {
-            index: index,
-            socket: socket,
-            _phantom: PhantomData,
-        }
+impl<'a> NamedLeds<'a> {
+    // The red LED.
+    pub fn error(&mut self) -> Option<&mut LED> {
+        self.by_kind("error")
     }
 
-    pub fn output(&mut self, value: bool) -> io::Result<()> {
-        let mut sock = self.socket.lock().unwrap();
-        if value {
-            sock.write_command(Command::GpioHigh(self.index as u8))
-        } else {
-            sock.write_command(Command::GpioLow(self.index as u8))
-        }
+    // The amber LED.
+    pub fn wlan(&mut self) -> Option<&mut LED> {
+        self.by_kind("wlan")
     }
 
-    pub fn high(&mut self) -> io::Result<()> {
-        self.output(true)
+    // The green LED.
+    pub fn user1(&mut self) -> Option<&mut LED> {
+        self.by_kind("user1")
     }
 
-    pub fn low(&mut self) -> io::Result<()> {
-        self.output(false)
+    // The blue LED.
+    pub fn user2(&mut self) -> Option<&mut LED> {
+        self.by_kind("user2")
+    }
+
+    fn by_kind(&mut self, kind: &str) -> Option<&mut LED> {
+        self.leds.iter_mut().find(|led| led.kind == kind)
     }
 }
 
-/// An I2C Port.
-pub struct I2cPort<'a> {
-    socket: Arc<Mutex<PortSocket>>,
-    _phantom: PhantomData<&'a Port>,
+/// The two module ports of a Tessel.
+/// # Example
+/// ```rust,no_run
+/// use tessel::PortGroup;
+///
+/// // Connect to a port daemon listening somewhere other than /var/run/tessel.
+/// let ports = PortGroup::with_paths("/tmp/port_a", "/tmp/port_b").unwrap();
+/// let (i2c, _) = ports.a.i2c().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct PortGroup {
+    pub a: Port,
+    pub b: Port,
 }
 
-impl<'p> I2cPort<'p> {
-    // TODO: make frequency optional
-    fn new<'a>(socket: Arc<Mutex<PortSocket>>) -> I2cPort<'a> {
-        let mut i2c = I2cPort {
-            socket: socket,
-            _phantom: PhantomData,
-        };
+impl PortGroup {
+    // with_paths() connects to port daemon sockets at non-default paths.
+    pub fn with_paths<P: AsRef<Path>, Q: AsRef<Path>>(a_path: P, b_path: Q) -> Result<PortGroup, TesselError> {
+        Ok(PortGroup {
+            a: try!(Port::try_new(a_path)),
+            b: try!(Port::try_new(b_path)),
+        })
+    }
 
-        // Use 100Khz as default frequency.
-        i2c.enable(I2cPort::compute_baud(100_000));
+    // Iterates over port A, then port B.
+    pub fn iter(&self) -> impl Iterator<Item = &Port> {
+        vec![&self.a, &self.b].into_iter()
+    }
 
-        i2c
+    // Returns the port labeled `letter` ('a' or 'b', in either case).
+    pub fn get(&self, letter: char) -> Option<&Port> {
+        match letter.to_ascii_lowercase() {
+            'a' => Some(&self.a),
+            'b' => Some(&self.b),
+            _ => None,
+        }
     }
+}
 
-    /// Computes the baudrate as used on the Atmel SAMD21 I2C register
-    /// to set the frequency of the I2C Clock.
-    fn compute_baud(frequency: u32) -> u8 {
-        let mut intermediate: f64 = MCU_MAX_SPEED as f64 / frequency as f64;
-        intermediate = intermediate - MCU_MAX_SPEED as f64 * MCU_MAX_SCL_RISE_TIME_NS;
-        // TODO: Do not hardcode these numbers
-        intermediate = intermediate / MCU_MAGIC_DIV_FACTOR_FOR_I2C_BAUD as f64 -
-                       MCU_MAGIC_SUBTRACT_FACTOR_FOR_I2C_BAUD as f64;
+// Consumes the group, yielding port A, then port B, e.g. to enable the same
+// peripheral on each.
+impl IntoIterator for PortGroup {
+    type Item = Port;
+    type IntoIter = ::std::vec::IntoIter<Port>;
 
-        // Return either the intermediate value or 255
-        let low = intermediate.min(u8::max_value() as f64);
+    fn into_iter(self) -> Self::IntoIter {
+        vec![self.a, self.b].into_iter()
+    }
+}
 
-        // If we have a potentially negative register value
-        // Casting as i64 because .float does not seem to work
-        if (low as i64) < u8::min_value() as i64 {
-            // Use 0 instead
-            return u8::min_value();
-        } else {
-            // Return the new register value
-            return low as u8;
+/// Clock characteristics of a port's coprocessor, used to compute the baud,
+/// clock and period registers for I2C, SPI, UART and PWM.
+///
+/// `McuProfile::tessel2()` describes the SAMD21 on a stock Tessel 2; other
+/// board revisions or custom firmware can pass their own to
+/// `Port::try_with_profile()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct McuProfile {
+    /// The peripheral clock speed, in Hz.
+    pub clock_speed: u32,
+    /// The worst-case SCL rise time, in seconds.
+    pub scl_rise_time: f64,
+    /// The I2C baud register is computed as
+    /// `(clock / scl - clock * rise) / i2c_baud_divisor - i2c_baud_offset`.
+    pub i2c_baud_divisor: u8,
+    pub i2c_baud_offset: u8,
+}
+
+impl McuProfile {
+    /// The SAMD21 running at 48MHz, as on the Tessel 2.
+    pub fn tessel2() -> McuProfile {
+        McuProfile {
+            clock_speed: 48_000_000,
+            scl_rise_time: 1.5e-8,
+            i2c_baud_divisor: 2,
+            i2c_baud_offset: 5,
         }
     }
+}
 
-    fn enable(&mut self, baud: u8) {
-        let mut sock = self.socket.lock().unwrap();
-        sock.write_command(Command::EnableI2c { baud: baud }).unwrap();
+impl Default for McuProfile {
+    fn default() -> McuProfile {
+        McuProfile::tessel2()
     }
+}
 
-    fn tx(sock: &mut MutexGuard<PortSocket>, address: u8, write_buf: &[u8]) {
-        sock.write_command(Command::Start(address<<1)).unwrap();
-        // Write the command and data
-        sock.write_command(Command::Tx(write_buf)).unwrap();
-    }
+/// A Port is a model of the Tessel hardware ports.
+///
+/// # Pin capabilities
+///
+/// | Pin | Digital | Pull | Interrupt | Analog | PWM |
+/// |-----|---------|------|-----------|--------|-----|
+/// | 0   | yes     |      |           |        |     |
+/// | 1   | yes     |      |           |        |     |
+/// | 2   | yes     | yes  | yes       |        |     |
+/// | 3   | yes     | yes  |           |        |     |
+/// | 4   | yes     | yes  |           | yes    |     |
+/// | 5   | yes     | yes  | yes       | yes    | yes |
+/// | 6   | yes     | yes  | yes       | yes    | yes |
+/// | 7   | yes     | yes  | yes       | yes    |     |
+///
+/// `pwm_pin()`, `analog_pin()`, and `interrupt_pin()` only accept pins with
+/// the matching capability, so the check happens at compile time.
+///
+/// # Sharing
+///
+/// Clones of a `Port` share one socket and one set of pin locks. A pin held
+/// through one clone is busy for all of them, and commands from different
+/// clones are serialized by the socket's mutex, so each command and its reply
+/// stay together. Ports, pins, and peripherals are `Send`, so a clone can be
+/// moved to another thread.
+///
+/// Only one of I2C, SPI, and UART can be enabled at a time across all clones;
+/// enabling another fails with `PortBusy` until the first is dropped.
+///
+/// # Blocking
+///
+/// Every call blocks until the coprocessor replies. With the `tokio`
+/// feature, `AsyncPort` offers non-blocking I2C for async programs instead.
+///
+/// # Example
+/// ```
+/// use tessel::Port;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Port {
+    socket: Arc<Mutex<PortSocket>>,
+    pins: Arc<PinLocks>,
+}
 
-    fn rx(sock: &mut MutexGuard<PortSocket>, address: u8, read_buf: &mut [u8]) {
-        sock.write_command(Command::Start(address << 1 | 1)).unwrap();
-        // Write the command and transfer length
-        sock.write_command(Command::Rx(read_buf.len() as u8)).unwrap();
+impl Port {
+    pub fn new<P: AsRef<Path>>(path: P) -> Port {
+        Port::try_new(path).expect("Could not connect to port socket.")
     }
 
-    fn stop(sock: &mut MutexGuard<PortSocket>) {
-        // Tell I2C to send STOP condition
-        sock.write_command(Command::Stop).unwrap();
+    pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Port, TesselError> {
+        Port::try_with_profile(path, McuProfile::tessel2())
     }
 
-    pub fn set_frequency(&mut self, frequency: u32) {
-        self.enable(I2cPort::compute_baud(frequency));
+    /// Like `try_new()`, but if the port daemon hasn't created its socket yet,
+    /// e.g. at boot, retries up to `attempts` times in all. Waits `delay`
+    /// before the first retry and doubles the wait after each one, until
+    /// doubling would overflow a `Duration`.
+    pub fn connect_with_retry<P: AsRef<Path>>(path: P, attempts: u32, delay: Duration) -> Result<Port, TesselError> {
+        let mut delay = delay;
+        let mut attempt = 1;
+        loop {
+            match Port::try_new(path.as_ref()) {
+                Err(TesselError::Io(ref err)) if attempt < attempts &&
+                    (err.kind() == io::ErrorKind::NotFound || err.kind() == io::ErrorKind::ConnectionRefused) => {}
+                result => return result,
+            }
+            thread::sleep(delay);
+            delay = delay.checked_mul(2).unwrap_or(delay);
+            attempt += 1;
+        }
     }
 
-    pub fn send(&mut self, address: u8, write_buf: &[u8]) {
-        let mut sock = self.socket.lock().unwrap();
-        I2cPort::tx(&mut sock, address, write_buf);
-        I2cPort::stop(&mut sock);
+    /// Like `try_new()`, but for a coprocessor with different clock
+    /// characteristics than the Tessel 2's.
+    pub fn try_with_profile<P: AsRef<Path>>(path: P, profile: McuProfile) -> Result<Port, TesselError> {
+        let mut socket = try!(PortSocket::try_new(path));
+        socket.set_profile(profile);
+        Ok(Port::new_with_socket(socket))
     }
 
-    pub fn read(&mut self, address: u8, read_buf: &mut [u8]) -> io::Result<()> {
-        let mut sock = self.socket.lock().unwrap();
-        I2cPort::rx(&mut sock, address, read_buf);
-        I2cPort::stop(&mut sock);
+    /// Returns the path of the port daemon's socket, e.g. for logging, or
+    /// `None` if the port wasn't opened from a path.
+    pub fn socket_path(&self) -> Option<PathBuf> {
+        self.socket.lock().unwrap().path().map(|path| path.to_path_buf())
+    }
 
-        // TODO: this is not how async reads should be handled.
-        // Read in first byte.
-        let mut read_byte = [0];
-        try!(sock.read_exact(&mut read_byte));
-        assert_eq!(read_byte[0], reply::DATA.0);
-        // Read in data from the socket
-        return sock.read_exact(read_buf);
+    /// Returns the clock characteristics of the port's coprocessor.
+    pub fn profile(&self) -> McuProfile {
+        self.socket.lock().unwrap().profile()
     }
 
-    pub fn transfer(&mut self, address: u8, write_buf: &[u8], read_buf: &mut [u8]) -> io::Result<()> {
-        let mut sock = self.socket.lock().unwrap();
-        I2cPort::tx(&mut sock, address, write_buf);
-        I2cPort::rx(&mut sock, address, read_buf);
-        I2cPort::stop(&mut sock);
+    fn new_with_socket(socket: PortSocket) -> Port {
+        // Create and return the port struct
+        Port {
+            socket: Arc::new(Mutex::new(socket)),
+            pins: Arc::new(PinLocks::new()),
+        }
+    }
 
-        // TODO: this is not how async reads should be handled.
-        // Read in first byte.
-        let mut read_byte = [0];
-        try!(sock.read_exact(&mut read_byte));
-        assert_eq!(read_byte[0], reply::DATA.0);
-        // Read in data from the socket
-        return sock.read_exact(read_buf);
+    /// Returns pins 5, 6, and 7. Fails with `PinBusy` if any of them is
+    /// already held, e.g. through a clone of the port; the pins taken
+    /// before it are released again.
+    pub fn pins<'a>(&'a self) -> Result<(Pin<'a>, Pin<'a>, Pin<'a>), TesselError> {
+        let pin5 = try!(self.pin(5));
+        let pin6 = try!(self.pin(6));
+        let pin7 = try!(self.pin(7));
+        Ok((pin5, pin6, pin7))
     }
-}
 
-// TODO: Figure out how to override the path secretly so the example
-// can actually be run.
-/// A LED models an LED on the Tessel board.
-/// # Example
-/// ```rust,no_run
-/// use tessel::LED;
-///
-/// let mut led = LED::new("red", "error");
-/// // LEDs are off by default.
-/// assert_eq!(false, led.read());
-/// led.on().unwrap();
-/// assert_eq!(true, led.read());
-pub struct LED {
-    // The file object we write to in order to change state.
-    file: File,
-    // The current value of the LED, defaults to false.
-    value: bool,
-}
+    /// Returns the pin at `index`, or `TesselError::UnsupportedPin` if the
+    /// port has no such pin. A pin can only be held by one `Pin` at a time;
+    /// if it is already held, returns `TesselError::PinBusy`.
+    pub fn pin<'a>(&'a self, index: usize) -> Result<Pin<'a>, TesselError> {
+        self.take_pin(index)
+    }
 
-impl LED {
-    pub fn new(color: &'static str, kind: &'static str) -> LED {
-        let path = format!("/sys/devices/leds/leds/tessel:{}:{}/brightness",
-                           color,
-                           kind);
+    /// Like `pin()`, but the pin doesn't borrow the port, so it can be
+    /// stored next to it or outlive it, e.g. in a driver that owns its pins
+    /// for the life of the program. The pin shares the port's socket and is
+    /// held until it is dropped, as with `pin()`.
+    pub fn take_pin(&self, index: usize) -> Result<Pin<'static>, TesselError> {
+        try!(Port::validate_pin(index));
+        if !self.pins.try_acquire(index) {
+            return Err(TesselError::PinBusy);
+        }
+        Ok(Pin::new_held(index, self.socket.clone(), self.pins.clone()))
+    }
 
-        // Open the file for write operations.
-        LED::new_with_file(File::create(path).unwrap())
+    /// Returns every pin on the port, in order. Fails with `PinBusy` if any
+    /// of them is already held.
+    pub fn all_pins<'a>(&'a self) -> Result<Vec<Pin<'a>>, TesselError> {
+        (0..PORT_PIN_COUNT).map(|index| self.pin(index)).collect()
     }
 
+    /// Returns a pin that can output PWM.
+    pub fn pwm_pin<'a>(&'a self, index: PwmPinIndex) -> Result<Pin<'a>, TesselError> {
+        self.pin(index as usize)
+    }
 
-    fn new_with_file(file: File) -> LED {
-        let mut led = LED {
-            value: false,
-            file: file,
-        };
+    /// Returns a pin that can read analog values.
+    pub fn analog_pin<'a>(&'a self, index: AnalogPinIndex) -> Result<Pin<'a>, TesselError> {
+        self.pin(index as usize)
+    }
 
-        // Turn the LED off by default.
-        led.off().unwrap();
+    /// Returns a pin that can wait for edges.
+    pub fn interrupt_pin<'a>(&'a self, index: InterruptPinIndex) -> Result<Pin<'a>, TesselError> {
+        self.pin(index as usize)
+    }
 
-        led
+    /// Like `pin()`, but blocks until the pin is released if it is held.
+    pub fn pin_blocking<'a>(&'a self, index: usize) -> Result<Pin<'a>, TesselError> {
+        try!(Port::validate_pin(index));
+        try!(self.pins.acquire(index));
+        Ok(Pin::new_held(index, self.socket.clone(), self.pins.clone()))
     }
 
-    // Turn the LED on (same as `high`).
-    pub fn on(&mut self) -> Result<(), io::Error> {
-        self.high()
+    /// Sets how long reads from the coprocessor wait before failing with
+    /// `TimedOut`, for every pin and peripheral on the port. `None`, the
+    /// default, waits forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), TesselError> {
+        self.socket.lock().unwrap().set_read_timeout(timeout)
     }
 
-    // Turn the LED off (same as `low`).
-    pub fn off(&mut self) -> Result<(), io::Error> {
-        self.low()
+    /// Locks and returns the port's socket, for sending commands this crate
+    /// has no API for yet. See `PortSocket::command()`.
+    pub fn socket<'a>(&'a self) -> MutexGuard<'a, PortSocket> {
+        self.socket.lock().unwrap()
     }
 
-    // Turn the LED on.
-    pub fn high(&mut self) -> Result<(), io::Error> {
-        self.write(true)
+    /// Writes raw bytes to the coprocessor, for custom protocols and
+    /// firmware bring-up. Nothing checks that they form valid commands.
+    pub fn raw_write(&self, bytes: &[u8]) -> Result<(), TesselError> {
+        self.socket.lock().unwrap().raw_write(bytes)
     }
 
-    // Turn the LED off.
-    pub fn low(&mut self) -> Result<(), io::Error> {
-        self.write(false)
+    /// Reads raw bytes from the coprocessor until `buf` is full. Replies and
+    /// asynchronous frames alike are returned as they arrive.
+    pub fn raw_read(&self, buf: &mut [u8]) -> Result<(), TesselError> {
+        self.socket.lock().unwrap().read_exact(buf)
     }
 
-    // Sets the LED to the opposite of its current state.
-    pub fn toggle(&mut self) -> Result<(), io::Error> {
-        let new_value = !self.value;
-        self.write(new_value)
+    /// Reads the raw 12-bit ADC values of several analog pins with one
+    /// command, in the order the pins are given. The pins don't need to be
+    /// held, as reading doesn't change them.
+    pub fn analog_read_many(&self, pins: &[usize]) -> Result<Vec<u16>, TesselError> {
+        if pins.iter().any(|pin| !ANALOG_PINS.contains(pin)) {
+            return Err(TesselError::UnsupportedPin);
+        }
+        if pins.is_empty() {
+            return Ok(vec![]);
+        }
+        if pins.len() > u8::max_value() as usize {
+            return Err(TesselError::InvalidArgument("Too many pins for one analog read."));
+        }
+
+        let pin_bytes: Vec<u8> = pins.iter().map(|&pin| pin as u8).collect();
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::AnalogReadMulti(&pin_bytes)));
+
+        // Each sample is sent little-endian, as for a single analog read.
+        let mut data = vec![0; pins.len() * 2];
+        try!(sock.read_data(&mut data));
+        Ok(data.chunks(2).map(|pair| (pair[0] as u16) | ((pair[1] as u16) << 8)).collect())
     }
 
-    // Returns the current state of the LED.
-    pub fn read(&self) -> bool {
-        self.value
+    /// Returns what pin `index` can do, so generic code can reject an
+    /// unsupported operation before trying it. Pins that don't exist can do
+    /// nothing. On Tessel 2:
+    ///
+    /// | Pin | Capabilities           |
+    /// |-----|------------------------|
+    /// | 0   | I2C (SCL)              |
+    /// | 1   | I2C (SDA)              |
+    /// | 2   | SPI (SCK), interrupt   |
+    /// | 3   | SPI (MISO)             |
+    /// | 4   | SPI (MOSI), analog     |
+    /// | 5   | analog, PWM, interrupt |
+    /// | 6   | analog, PWM, interrupt |
+    /// | 7   | analog, interrupt      |
+    pub fn pin_capabilities(&self, index: usize) -> PinCapabilities {
+        PinCapabilities::tessel2(index)
     }
 
-    // Helper function to write new state to LED filepath.
-    fn write(&mut self, new_value: bool) -> Result<(), io::Error> {
-        // Save the new value to the model.
-        self.value = new_value;
-        // Return the binary representation of that value type.
-        let string_value = match new_value {
-            true => b'1',
-            false => b'0',
-        };
+    /// Reads the pins selected by `mask` (bit `n` for pin `n`) with one
+    /// command, so the levels are sampled together, e.g. the data lines of
+    /// a parallel bus. Returns their levels packed the same way; bits
+    /// outside `mask` are 0.
+    ///
+    /// Reading doesn't disturb other users, so unlike `write_pins()` this
+    /// doesn't check locks: pins held by a `Pin` or used by an enabled
+    /// peripheral are read all the same.
+    pub fn read_pins(&self, mask: u8) -> Result<u8, TesselError> {
+        if mask == 0 {
+            return Ok(0);
+        }
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::GpioReadMask(mask)));
 
-        // Write that data to the file and return the result.
-        self.file.write_all(&[string_value])
+        let mut levels = [0];
+        try!(sock.read_data(&mut levels));
+        Ok(levels[0] & mask)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate tempfile;
-    use super::*;
-    use std::io::{Read, Seek, SeekFrom};
+    /// Drives the pins selected by `mask` to the matching bits of `values`
+    /// with one command, so they change together. Pins outside `mask` are
+    /// left alone. `Pin::last_written()` doesn't see these writes.
+    ///
+    /// Fails with `PortBusy` if `mask` includes a pin of the enabled
+    /// peripheral (e.g. SCL or SDA while I2C is enabled), or `PinBusy` if it
+    /// includes a pin held by a `Pin`.
+    pub fn write_pins(&self, mask: u8, values: u8) -> Result<(), TesselError> {
+        if mask == 0 {
+            return Ok(());
+        }
+        if Port::peripheral_pins(self.mode()).iter().any(|&pin| mask & (1 << pin) != 0) {
+            return Err(TesselError::PortBusy);
+        }
+        if self.pins.held.lock().unwrap().iter().any(|pin| mask & (1 << pin) != 0) {
+            return Err(TesselError::PinBusy);
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        sock.write_command(Command::GpioWriteMask { mask: mask, values: values & mask })
+    }
+
+    /// Asks the coprocessor for its firmware version, as (major, minor,
+    /// patch), to check that it supports a feature before using it. Returns
+    /// `None` if the firmware predates the version command and rejects it.
+    pub fn firmware_version(&self) -> Result<Option<(u8, u8, u8)>, TesselError> {
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::GetVersion));
+
+        match try!(sock.read_reply()) {
+            x if x == reply::DATA.0 => {
+                let mut version = [0; 3];
+                try!(sock.read_exact(&mut version));
+                Ok(Some((version[0], version[1], version[2])))
+            }
+            x if x == reply::NACK.0 => Ok(None),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    /// Resets the coprocessor's side of the port after an error: disables
+    /// whichever peripheral is enabled, returns every pin to an input, and
+    /// discards any reply left waiting on the socket. `mode()` reports
+    /// `PortMode::Gpio` afterwards.
+    ///
+    /// Peripheral handles from before the reset no longer disable anything
+    /// when dropped, but shouldn't be used; a `Pin`'s `last_written()` goes
+    /// stale.
+    pub fn reset(&self) -> Result<(), TesselError> {
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.drain());
+        try!(sock.write_command(Command::Reset));
+        self.pins.switch_mode(PortMode::Gpio);
+        Ok(())
+    }
+
+    /// Disables the current peripheral and enables the one `config` asks
+    /// for, returning its handle. The socket stays locked throughout, so no
+    /// other command gets in between. `PortConfig::Gpio` leaves the port
+    /// free.
+    ///
+    /// Waits for the coprocessor to answer a ping after the disable, so the
+    /// old peripheral is off before the new one is enabled. Fails with
+    /// `PinBusy`, leaving the old peripheral enabled, if a pin the new one
+    /// needs is held; the old peripheral's handle holds its own pins until
+    /// it is dropped. That handle no longer disables anything when dropped,
+    /// but shouldn't be used.
+    pub fn switch_to<'b>(&self, config: PortConfig) -> Result<Peripheral<'b>, TesselError> {
+        let mode = config.mode();
+        let mut pins = vec![];
+        for &index in Port::peripheral_pins(mode) {
+            pins.push(try!(self.take_pin(index)));
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        if let Some(command) = Port::disable_command(self.mode()) {
+            try!(sock.write_command(command));
+        }
+        try!(Port::ping_socket(&mut sock));
+        let claim = self.pins.switch_mode(mode);
+
+        let (socket, locks) = (self.socket.clone(), self.pins.clone());
+        let mut pins = pins.into_iter();
+        let mut pin = || pins.next().unwrap();
+        let (peripheral, enabled) = match config {
+            PortConfig::Gpio => (Peripheral::Gpio, Ok(())),
+            PortConfig::I2c(config) => {
+                let i2c = I2cPort::new(socket, locks, claim, (pin(), pin()), config);
+                (Peripheral::I2c(i2c), I2cPort::enable_socket(&mut sock, config))
+            }
+            PortConfig::Spi(config) => {
+                let spi = SpiPort::new(socket, locks, claim, (pin(), pin(), pin()), config);
+                (Peripheral::Spi(spi), SpiPort::enable_socket(&mut sock, config))
+            }
+            PortConfig::Uart(baud) => {
+                let uart = UartPort::new(socket, locks, claim, (pin(), pin()), baud);
+                (Peripheral::Uart(uart), UartPort::enable_socket(&mut sock, baud))
+            }
+        };
+        // If enabling failed, dropping the half-built handle releases the
+        // claim again, which needs the socket.
+        drop(sock);
+        try!(enabled);
+        Ok(peripheral)
+    }
+
+    /// Checks that the coprocessor is still answering, e.g. from a supervisor
+    /// that reconnects or resets the port when it isn't. The round trip goes
+    /// through the port daemon and the SPI bridge to the coprocessor, and
+    /// normally takes around a millisecond; it takes longer while another
+    /// thread is using the port, since the ping waits for the socket.
+    ///
+    /// Fails with `TimedOut` after the port's read timeout, or after one
+    /// second if it has none.
+    pub fn ping(&self) -> Result<(), TesselError> {
+        Port::ping_socket(&mut self.socket.lock().unwrap())
+    }
+
+    fn ping_socket(sock: &mut PortSocket) -> Result<(), TesselError> {
+        try!(sock.write_command(Command::Ping));
+
+        let timeout = match sock.read_timeout() {
+            Some(_) => None,
+            None => Some(Duration::from_millis(PING_TIMEOUT_MS)),
+        };
+        match try!(sock.with_read_timeout(timeout, |sock| sock.read_reply())) {
+            x if x == reply::PONG.0 => Ok(()),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    /// Reconnects to the port daemon, e.g. after it restarted. Writes also
+    /// reconnect once on their own if the connection was lost.
+    pub fn reconnect(&self) -> Result<(), TesselError> {
+        self.socket.lock().unwrap().reconnect()
+    }
+
+    fn validate_pin(index: usize) -> Result<(), TesselError> {
+        if index >= PORT_PIN_COUNT {
+            return Err(TesselError::UnsupportedPin);
+        }
+        Ok(())
+    }
+
+    /// Returns which peripheral is enabled on the port, shared by all of its
+    /// clones. `Gpio` means none is, so any of them can be enabled.
+    pub fn mode(&self) -> PortMode {
+        self.pins.mode.lock().unwrap().mode
+    }
+
+    /// Disables whichever peripheral `mode()` reports on the coprocessor,
+    /// e.g. from shutdown code that doesn't own the peripheral's handle. The
+    /// port stays in that mode until the handle is dropped.
+    pub fn disable_peripheral(&self) -> Result<(), TesselError> {
+        match Port::disable_command(self.mode()) {
+            Some(command) => self.socket.lock().unwrap().write_command(command),
+            None => Ok(()),
+        }
+    }
+
+    // The pins the peripheral for `mode` drives.
+    fn peripheral_pins(mode: PortMode) -> &'static [usize] {
+        match mode {
+            PortMode::Gpio => &[],
+            PortMode::I2c => &I2C_PINS,
+            PortMode::Spi => &SPI_PINS,
+            PortMode::Uart => &UART_PINS,
+        }
+    }
+
+    fn disable_command(mode: PortMode) -> Option<Command<'static>> {
+        match mode {
+            PortMode::Gpio => None,
+            PortMode::I2c => Some(Command::DisableI2c),
+            PortMode::Spi => Some(Command::DisableSpi),
+            PortMode::Uart => Some(Command::DisableUart),
+        }
+    }
+
+    // i2c() enables I2C at the standard mode frequency. Use i2c_with_config()
+    // for other frequencies. Fails with PinBusy if SCL (pin 0) or SDA (pin 1)
+    // is held, and holds both until the I2cPort is dropped or freed.
+    pub fn i2c<'b>(self) -> Result<(I2cPort<'b>, Gpio<'b>), TesselError> {
+        self.i2c_with_config(I2cConfig::default())
+    }
+
+    // i2c_default() is i2c() under the name that says which frequency it
+    // picks: I2C_STANDARD_MODE, with the other I2cConfig defaults.
+    pub fn i2c_default<'b>(self) -> Result<(I2cPort<'b>, Gpio<'b>), TesselError> {
+        self.i2c_with_config(I2cConfig::default())
+    }
+
+    pub fn i2c_with_config<'b>(self, config: I2cConfig) -> Result<(I2cPort<'b>, Gpio<'b>), TesselError> {
+        // SCL and SDA stay held for as long as I2C is enabled.
+        let scl = try!(self.take_pin(I2C_PINS[0]));
+        let sda = try!(self.take_pin(I2C_PINS[1]));
+        let claim = try!(self.pins.claim(PortMode::I2c));
+        let mut available = BitSet::new();
+        for i in 2..8 {
+            available.insert(i);
+        }
+        let i2c = I2cPort::new(self.socket.clone(), self.pins.clone(), claim, (scl, sda), config);
+        {
+            // If this fails, dropping the half-built port releases the claim.
+            let mut sock = self.socket.lock().unwrap();
+            try!(I2cPort::enable_socket(&mut sock, config));
+        }
+        Ok((i2c, Gpio::new(self.socket.clone(), available)))
+    }
+
+    /// Enables I2C at `frequency` for the duration of `f`, then disables it,
+    /// so pins 0 and 1 can go back to GPIO use without giving up the `Port`.
+    /// Fails with `PinBusy` if either pin is held, and holds both meanwhile.
+    pub fn with_i2c<T, F>(&self, frequency: u32, f: F) -> Result<T, TesselError>
+        where F: FnOnce(&mut I2cPort) -> Result<T, TesselError>
+    {
+        let config = I2cConfig::default().with_frequency(Some(frequency));
+        let (mut i2c, _) = try!(self.clone().i2c_with_config(config));
+        f(&mut i2c)
+    }
+
+    /// Like `i2c_with_config()`, but fails with `InvalidArgument` instead of
+    /// enabling I2C if the baud register can't get within `tolerance` of the
+    /// requested frequency (e.g. 0.05 for 5%). `i2c_with_config()` clamps to
+    /// the nearest frequency the hardware supports.
+    pub fn try_i2c<'b>(self, config: I2cConfig, tolerance: f64) -> Result<(I2cPort<'b>, Gpio<'b>), TesselError> {
+        try!(I2cPort::check_frequency(&self.profile(), config.frequency, tolerance));
+        self.i2c_with_config(config)
+    }
+
+    // spi() enables SPI with the given clock and mode. Fails with PinBusy if
+    // SCK (pin 2), MISO (pin 3) or MOSI (pin 4) is held, and holds all three
+    // until the SpiPort is dropped or freed.
+    pub fn spi<'b>(self, clock_speed: u32, mode: SpiMode) -> Result<(SpiPort<'b>, Gpio<'b>), TesselError> {
+        self.spi_with_config(SpiConfig::default().with_clock_speed(clock_speed).with_mode(mode))
+    }
+
+    pub fn spi_with_config<'b>(self, config: SpiConfig) -> Result<(SpiPort<'b>, Gpio<'b>), TesselError> {
+        // SCK, MISO, and MOSI stay held for as long as SPI is enabled.
+        let sck = try!(self.take_pin(SPI_PINS[0]));
+        let miso = try!(self.take_pin(SPI_PINS[1]));
+        let mosi = try!(self.take_pin(SPI_PINS[2]));
+        let claim = try!(self.pins.claim(PortMode::Spi));
+        let mut available = BitSet::new();
+        for i in (0..2).chain(5..8) {
+            available.insert(i);
+        }
+        let spi = SpiPort::new(self.socket.clone(), self.pins.clone(), claim, (sck, miso, mosi), config);
+        {
+            // If this fails, dropping the half-built port releases the claim.
+            let mut sock = self.socket.lock().unwrap();
+            try!(SpiPort::enable_socket(&mut sock, config));
+        }
+        Ok((spi, Gpio::new(self.socket.clone(), available)))
+    }
+
+    // uart() enables the UART at `baud`. Fails with PinBusy if TX (pin 5) or
+    // RX (pin 6) is held, and holds both until the UartPort is dropped or
+    // freed.
+    pub fn uart<'b>(self, baud: u32) -> Result<(UartPort<'b>, Gpio<'b>), TesselError> {
+        // TX and RX stay held for as long as the UART is enabled.
+        let tx = try!(self.take_pin(UART_PINS[0]));
+        let rx = try!(self.take_pin(UART_PINS[1]));
+        let claim = try!(self.pins.claim(PortMode::Uart));
+        let mut available = BitSet::new();
+        for i in (0..5).chain(7..8) {
+            available.insert(i);
+        }
+        let uart = UartPort::new(self.socket.clone(), self.pins.clone(), claim, (tx, rx), baud);
+        {
+            // If this fails, dropping the half-built port releases the claim.
+            let mut sock = self.socket.lock().unwrap();
+            try!(UartPort::enable_socket(&mut sock, baud));
+        }
+        Ok((uart, Gpio::new(self.socket.clone(), available)))
+    }
+
+    /// Bit-bangs an I2C master on any two pins, e.g. when the hardware I2C
+    /// is in use or a device is wired to other pins. The pins are held until
+    /// the `SoftI2c` is dropped or freed. Every bit takes several round trips
+    /// to the coprocessor, so the bus runs far slower than `frequency` on
+    /// real hardware; `frequency` is an upper bound.
+    pub fn soft_i2c<'a>(&'a self, scl: usize, sda: usize, frequency: u32) -> Result<SoftI2c<'a>, TesselError> {
+        if scl == sda {
+            return Err(TesselError::InvalidArgument("SCL and SDA must be different pins."));
+        }
+        if frequency == 0 {
+            return Err(TesselError::InvalidArgument("I2C frequency must be above 0 Hz."));
+        }
+        let scl = try!(self.pin(scl));
+        let sda = try!(self.pin(sda));
+        SoftI2c::new(scl, sda, frequency)
+    }
+}
+
+/// Exposes the port daemon's socket, e.g. to register it with epoll or mio
+/// and wake up when the coprocessor sends something.
+///
+/// The descriptor is shared with every handle on the port: reading from or
+/// writing to it directly, or doing so while a blocking method is running on
+/// another thread, desyncs the protocol, so coordinating that is up to the
+/// caller. The descriptor changes if the socket reconnects after the daemon
+/// restarts.
+///
+/// # Panics
+///
+/// If the port wraps a transport without a file descriptor, such as a mock
+/// passed to `PortSocket::from_transport()`.
+impl AsRawFd for Port {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.lock().unwrap().raw_fd().expect("Port's transport has no file descriptor.")
+    }
+}
+
+/// Which peripheral a `Port` is being used for. I2C, SPI, and UART share the
+/// port's SERCOM, so only one of them can be enabled at a time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PortMode {
+    /// No peripheral is enabled; the pins are plain GPIO.
+    Gpio,
+    I2c,
+    Spi,
+    Uart,
+}
+
+/// The peripheral `Port::switch_to()` enables, with its settings.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PortConfig {
+    /// No peripheral; the pins go back to plain GPIO.
+    Gpio,
+    I2c(I2cConfig),
+    Spi(SpiConfig),
+    /// The UART, at this baud rate.
+    Uart(u32),
+}
+
+impl PortConfig {
+    /// The mode the port is in once this is enabled.
+    pub fn mode(&self) -> PortMode {
+        match *self {
+            PortConfig::Gpio => PortMode::Gpio,
+            PortConfig::I2c(_) => PortMode::I2c,
+            PortConfig::Spi(_) => PortMode::Spi,
+            PortConfig::Uart(_) => PortMode::Uart,
+        }
+    }
+}
+
+/// The handle for the peripheral `Port::switch_to()` enabled.
+#[derive(Debug)]
+pub enum Peripheral<'a> {
+    Gpio,
+    I2c(I2cPort<'a>),
+    Spi(SpiPort<'a>),
+    Uart(UartPort<'a>),
+}
+
+// Tracks which pins of a port are held by a `Pin`, and which peripheral the
+// port is enabled for.
+#[derive(Debug)]
+struct PinLocks {
+    held: Mutex<BitSet>,
+    released: Condvar,
+    mode: Mutex<ModeState>,
+}
+
+// The peripheral a port is enabled for.
+#[derive(Debug)]
+struct ModeState {
+    mode: PortMode,
+    // Counts claims and switches, so a peripheral handle can tell whether
+    // the port was taken away from it.
+    claims: u64,
+}
+
+impl PinLocks {
+    fn new() -> PinLocks {
+        PinLocks {
+            held: Mutex::new(BitSet::new()),
+            released: Condvar::new(),
+            mode: Mutex::new(ModeState { mode: PortMode::Gpio, claims: 0 }),
+        }
+    }
+
+    // Marks the port as in use by a peripheral, or fails with PortBusy if
+    // another one already is. Returns the claim to pass to release_mode().
+    fn claim(&self, mode: PortMode) -> Result<u64, TesselError> {
+        let mut state = self.mode.lock().unwrap();
+        if state.mode != PortMode::Gpio {
+            return Err(TesselError::PortBusy);
+        }
+        state.mode = mode;
+        state.claims += 1;
+        Ok(state.claims)
+    }
+
+    // Marks the port free for another peripheral, unless it was switched or
+    // claimed again since `claim`. Returns whether the caller should disable
+    // its peripheral.
+    fn release_mode(&self, claim: u64) -> bool {
+        match self.mode.lock() {
+            Ok(ref mut state) if state.claims == claim => {
+                state.mode = PortMode::Gpio;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Takes the port away from the current peripheral and gives it to
+    // `mode`. Returns the new claim, as claim() does.
+    fn switch_mode(&self, mode: PortMode) -> u64 {
+        let mut state = self.mode.lock().unwrap();
+        state.mode = mode;
+        state.claims += 1;
+        state.claims
+    }
+
+    // Marks the pin as held, or returns false if it already is.
+    fn try_acquire(&self, index: usize) -> bool {
+        self.held.lock().unwrap().insert(index)
+    }
+
+    // Waits until the pin is not held, then marks it as held.
+    fn acquire(&self, index: usize) -> Result<(), TesselError> {
+        let poisoned = |_| TesselError::Io(io::Error::new(io::ErrorKind::Other, "Pin lock poisoned."));
+        let mut held = try!(self.held.lock().map_err(&poisoned));
+        while held.contains(index) {
+            held = try!(self.released.wait(held).map_err(&poisoned));
+        }
+        held.insert(index);
+        Ok(())
+    }
+
+    fn release(&self, index: usize) {
+        if let Ok(mut held) = self.held.lock() {
+            held.remove(index);
+        }
+        self.released.notify_all();
+    }
+}
+
+/// Gpio is a selection of pins.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Gpio<'a> {
+    socket: Arc<Mutex<PortSocket>>,
+    available: BitSet,
+    _phantom: PhantomData<&'a Port>,
+}
+
+impl<'a> Gpio<'a> {
+    pub fn new<'b>(socket: Arc<Mutex<PortSocket>>, available: BitSet) -> Gpio<'b> {
+        // Create and return the port struct
+        Gpio {
+            socket: socket,
+            available: available,
+            _phantom: PhantomData,
+        }
+    }
+
+    // TODO return iterator
+    //pub fn pins() { }
+
+    pub fn pin_select<H: PinSelect<'a>>(self, select: H) -> H::Output {
+        select.select(self.socket.clone())
+    }
+}
+
+/// Pin tuple conversion for gpio:pins(..)
+pub trait PinSelect<'a> {
+    type Output;
+    fn validate(&self, &BitSet<usize>) -> bool;
+    fn select(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output;
+}
+
+impl<'a> PinSelect<'a> for usize {
+    type Output = Pin<'a>;
+    fn validate(&self, set: &BitSet<usize>) -> bool {
+        set.contains(*self)
+    }
+    fn select<'b>(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output {
+        Pin::new(*self, socket)
+    }
+}
+
+impl<'a> PinSelect<'a> for (usize, usize) {
+    type Output = (Pin<'a>, Pin<'a>);
+    fn validate(&self, set: &BitSet<usize>) -> bool {
+        set.contains(self.0) || set.contains(self.1)
+    }
+    fn select<'b>(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output {
+        (Pin::new(self.0, socket.clone()), Pin::new(self.1, socket))
+    }
+}
+
+impl<'a> PinSelect<'a> for (usize, usize, usize) {
+    type Output = (Pin<'a>, Pin<'a>, Pin<'a>);
+    fn validate(&self, set: &BitSet<usize>) -> bool {
+        set.contains(self.0) || set.contains(self.1) || set.contains(self.2)
+    }
+    fn select<'b>(&self, socket: Arc<Mutex<PortSocket>>) -> Self::Output {
+        (Pin::new(self.0, socket.clone()), Pin::new(self.1, socket.clone()), Pin::new(self.2, socket))
+    }
+}
+
+/// A GPIO pin usable as an input or output.
+pub struct Pin<'a> {
+    index: usize,
+    socket: Arc<Mutex<PortSocket>>,
+    // Set when the pin was handed out by `Port::pin`, and released on drop.
+    locks: Option<Arc<PinLocks>>,
+    // The level last written, if any, defaults to None.
+    value: Option<bool>,
+    // The direction this Pin last set, or None if it hasn't set one or a
+    // peripheral (PWM, the ADC) has taken the pin over since.
+    mode: Cell<Option<PinMode>>,
+    // Pulse widths, in microseconds, for 0 and 180 degrees in servo_write().
+    servo_range: (u32, u32),
+    _phantom: PhantomData<&'a Port>,
+}
+
+impl<'a> fmt::Debug for Pin<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pin")
+            .field("index", &self.index)
+            .field("held", &self.locks.is_some())
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+impl<'a> Pin<'a> {
+    fn new<'b>(index: usize, socket: Arc<Mutex<PortSocket>>) -> Pin<'b> {
+        Pin {
+            index: index,
+            socket: socket,
+            locks: None,
+            value: None,
+            mode: Cell::new(None),
+            servo_range: SERVO_DEFAULT_RANGE_US,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn new_held<'b>(index: usize, socket: Arc<Mutex<PortSocket>>, locks: Arc<PinLocks>) -> Pin<'b> {
+        Pin {
+            index: index,
+            socket: socket,
+            locks: Some(locks),
+            value: None,
+            mode: Cell::new(None),
+            servo_range: SERVO_DEFAULT_RANGE_US,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn output(&mut self, value: bool) -> Result<(), TesselError> {
+        self.write(value)
+    }
+
+    /// Drives the pin high or low. If this `Pin` last made the pin an input,
+    /// it is switched back to an output first.
+    pub fn write(&mut self, high: bool) -> Result<(), TesselError> {
+        let mut sock = self.socket.lock().unwrap();
+        if self.mode.get() == Some(PinMode::Input) {
+            try!(sock.write_command(Command::GpioOutput(self.index as u8)));
+        }
+        if high {
+            try!(sock.write_command(Command::GpioHigh(self.index as u8)));
+        } else {
+            try!(sock.write_command(Command::GpioLow(self.index as u8)));
+        }
+        self.value = Some(high);
+        self.mode.set(Some(PinMode::Output));
+        Ok(())
+    }
+
+    /// Returns the level last passed to `write()`, or `None` if this `Pin`
+    /// hasn't written one. This is a cache kept without touching the bus, so
+    /// unlike `read()` it doesn't reflect what is actually on the pin, e.g.
+    /// when another device pulls it or a different `Pin` drives it.
+    pub fn last_written(&self) -> Option<bool> {
+        self.value
+    }
+
+    /// Reads the digital level of the pin. If this `Pin` last made the pin
+    /// an output, it is switched back to an input first.
+    pub fn read(&self) -> Result<bool, TesselError> {
+        let mut sock = self.socket.lock().unwrap();
+        if self.mode.get() == Some(PinMode::Output) {
+            try!(sock.write_command(Command::GpioInput(self.index as u8)));
+        }
+        try!(sock.write_command(Command::GpioIn(self.index as u8)));
+        self.mode.set(Some(PinMode::Input));
+
+        match try!(sock.read_reply()) {
+            x if x == reply::HIGH.0 => Ok(true),
+            x if x == reply::LOW.0 => Ok(false),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    // Reads the level on the pin without reconfiguring it as an input, so an
+    // open-drain output can sample the line it is releasing.
+    fn read_raw(&self) -> Result<bool, TesselError> {
+        Pin::read_raw_index(&mut self.socket.lock().unwrap(), self.index)
+    }
+
+    fn read_raw_index(sock: &mut PortSocket, index: usize) -> Result<bool, TesselError> {
+        try!(sock.write_command(Command::GpioRawRead(index as u8)));
+
+        match try!(sock.read_reply()) {
+            x if x == reply::HIGH.0 => Ok(true),
+            x if x == reply::LOW.0 => Ok(false),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    /// Drives the pin to `high` for `duration`, then to the opposite level,
+    /// e.g. to trigger an ultrasonic sensor. The coprocessor times the pulse,
+    /// so it isn't stretched by host scheduling; it has a resolution of one
+    /// microsecond, and `duration` is rounded down to whole microseconds.
+    /// Blocks until the pulse is over.
+    pub fn pulse(&mut self, high: bool, duration: Duration) -> Result<(), TesselError> {
+        let micros = duration.as_secs()
+            .checked_mul(1_000_000)
+            .and_then(|micros| micros.checked_add(duration.subsec_micros() as u64));
+        let micros = match micros {
+            Some(micros) if micros > 0 && micros <= u32::max_value() as u64 => micros as u32,
+            _ => return Err(TesselError::InvalidArgument("Pulse must last from 1us to about 71 minutes.")),
+        };
+
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::GpioPulse { pin: self.index as u8, high: high, micros: micros }));
+        // The coprocessor acknowledges once the pin is back.
+        let timeout = duration + Duration::from_secs(1);
+        let ack = try!(sock.with_read_timeout(Some(timeout), |sock| sock.read_reply()));
+        if ack != reply::ACK.0 {
+            return Err(TesselError::InvalidReply);
+        }
+        self.value = Some(!high);
+        Ok(())
+    }
+
+    /// Enables or disables the pin's internal pull resistor. Only pins 2
+    /// through 7 have pull resistors; pins 0 and 1 return an error.
+    pub fn set_pull(&mut self, pull: Pull) -> Result<(), TesselError> {
+        if !PULL_PINS.contains(&self.index) {
+            return Err(TesselError::UnsupportedPin);
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        sock.write_command(Command::GpioPull { pin: self.index as u8, mode: pull as u8 })
+    }
+
+    /// Blocks until the coprocessor reports a matching edge on the pin. The
+    /// port's other pins and peripherals can't be used while waiting.
+    pub fn wait_for_edge(&mut self, edge: Edge) -> Result<(), TesselError> {
+        self.wait_for_edge_within(edge, None)
+    }
+
+    /// Like `wait_for_edge()`, but fails with `TimedOut` if no matching edge
+    /// arrives within `timeout`.
+    pub fn wait_for_edge_timeout(&mut self, edge: Edge, timeout: Duration) -> Result<(), TesselError> {
+        self.wait_for_edge_within(edge, Some(timeout))
+    }
+
+    fn wait_for_edge_within(&mut self, edge: Edge, timeout: Option<Duration>) -> Result<(), TesselError> {
+        if !INTERRUPT_PINS.contains(&self.index) {
+            return Err(TesselError::UnsupportedPin);
+        }
+
+        let pin = self.index as u8;
+        let mut sock = self.socket.lock().unwrap();
+        // Only edges after this call count.
+        sock.discard_pin_changes(pin);
+        try!(sock.write_command(Command::GpioInt { pin: pin, mode: edge as u8 }));
+
+        let result = sock.with_read_timeout(timeout, |sock| {
+            loop {
+                match try!(sock.wait_pin_change(pin)) {
+                    false if edge == Edge::Rising => continue,
+                    true if edge == Edge::Falling => continue,
+                    _ => return Ok(()),
+                }
+            }
+        });
+
+        // Stop listening for interrupts on the pin.
+        try!(sock.write_command(Command::GpioInt { pin: pin, mode: 0 }));
+        result
+    }
+
+    /// Has the coprocessor count matching edges on the pin from zero, without
+    /// reporting each one to the host, e.g. for a tachometer. Only the pins
+    /// that support `wait_for_edge()` can count.
+    pub fn start_counter(&mut self, edge: Edge) -> Result<(), TesselError> {
+        if !INTERRUPT_PINS.contains(&self.index) {
+            return Err(TesselError::UnsupportedPin);
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        sock.write_command(Command::GpioCounterStart { pin: self.index as u8, mode: edge as u8 })
+    }
+
+    /// Returns how many edges were counted since `start_counter()`. The count
+    /// keeps running, and wraps around after `u32::max_value()`.
+    pub fn read_counter(&mut self) -> Result<u32, TesselError> {
+        if !INTERRUPT_PINS.contains(&self.index) {
+            return Err(TesselError::UnsupportedPin);
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::GpioCounterRead(self.index as u8)));
+
+        // The count is sent little-endian.
+        let mut count = [0; 4];
+        try!(sock.read_data(&mut count));
+        Ok(count.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32))
+    }
+
+    /// Counts rising edges for `window` and returns their frequency in Hz.
+    /// Blocks for the whole window.
+    pub fn measure_frequency(&mut self, window: Duration) -> Result<f64, TesselError> {
+        try!(self.start_counter(Edge::Rising));
+        let started = Instant::now();
+        thread::sleep(window);
+        let count = try!(self.read_counter());
+        // Use the time that actually passed, as the sleep may have overrun.
+        let elapsed = started.elapsed();
+        Ok(count as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9))
+    }
+
+    /// Outputs a PWM signal at `frequency` Hz with the given duty cycle.
+    /// The duty cycle is clamped to the range 0.0 to 1.0.
+    ///
+    /// Pins 5 and 6 share one timer, and so one period: setting a frequency
+    /// on either pin retimes both. The other pin keeps its duty cycle in
+    /// timer counts, not as a fraction, so its output changes too. Drive
+    /// both at the same frequency, e.g. two servos with `servo_write()`.
+    pub fn pwm(&mut self, frequency: u32, duty_cycle: f32) -> Result<(), TesselError> {
+        if !PWM_PINS.contains(&self.index) {
+            return Err(TesselError::UnsupportedPin);
+        }
+
+        let profile = self.socket.lock().unwrap().profile();
+        let (prescaler, period) = match Pin::compute_pwm_period(&profile, frequency) {
+            Some(x) => x,
+            None => return Err(TesselError::InvalidArgument("PWM frequency is outside the achievable range.")),
+        };
+        let duty_cycle = (period as f32 * duty_cycle.clamp(0.0, 1.0)) as u16;
+
+        let mut sock = self.socket.lock().unwrap();
+        self.mode.set(None);
+        try!(sock.write_command(Command::PwmPeriod { prescalar: prescaler, tcc_id: PWM_TCC_ID, period: period }));
+        sock.write_command(Command::PwmDutyCycle { pin: self.index as u8, duty_cycle: duty_cycle })
+    }
+
+    /// Turns a servo on this PWM pin to `angle`, from 0 to 180 degrees, by
+    /// sending a 50Hz pulse between the widths set with `set_servo_range()`
+    /// (1ms to 2ms by default). Angles outside the range are clamped.
+    pub fn servo_write(&mut self, angle: f32) -> Result<(), TesselError> {
+        let (min, max) = self.servo_range;
+        let angle = angle.clamp(0.0, 180.0);
+        let pulse_us = min as f32 + (max - min) as f32 * angle / 180.0;
+        let period_us = 1_000_000 / SERVO_FREQUENCY;
+        self.pwm(SERVO_FREQUENCY, pulse_us / period_us as f32)
+    }
+
+    /// Sets the pulse widths, in microseconds, `servo_write()` sends for 0
+    /// and 180 degrees, for servos that don't follow the 1ms to 2ms
+    /// convention. Fails with `InvalidArgument` unless `min_us` is below
+    /// `max_us` and both fit in the 20ms period.
+    pub fn set_servo_range(&mut self, min_us: u32, max_us: u32) -> Result<(), TesselError> {
+        if min_us >= max_us || max_us > 1_000_000 / SERVO_FREQUENCY {
+            return Err(TesselError::InvalidArgument("Servo pulse range must be increasing and within 20ms."));
+        }
+        self.servo_range = (min_us, max_us);
+        Ok(())
+    }
+
+    /// Switches the pin between input and output without reading or writing
+    /// it, e.g. for single-wire protocols that hand the line back and forth.
+    /// An output drives the level last written to the pin (low if none was).
+    pub fn set_mode(&mut self, mode: PinMode) -> Result<(), TesselError> {
+        let command = match mode {
+            PinMode::Input => Command::GpioInput(self.index as u8),
+            PinMode::Output => Command::GpioOutput(self.index as u8),
+        };
+        try!(self.socket.lock().unwrap().write_command(command));
+        self.mode.set(Some(mode));
+        Ok(())
+    }
+
+    /// Reads the raw 12-bit ADC value of the pin.
+    pub fn analog_read(&mut self) -> Result<u16, TesselError> {
+        if !ANALOG_PINS.contains(&self.index) {
+            return Err(TesselError::UnsupportedPin);
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        self.mode.set(None);
+        try!(sock.write_command(Command::AnalogRead(self.index as u8)));
+
+        // The sample is sent little-endian.
+        let mut value = [0; 2];
+        try!(sock.read_data(&mut value));
+        Ok((value[0] as u16) | ((value[1] as u16) << 8))
+    }
+
+    /// Reads the voltage on the pin, relative to the 3.3V analog reference.
+    pub fn analog_read_voltage(&mut self) -> Result<f32, TesselError> {
+        Ok(try!(self.analog_reading()).voltage)
+    }
+
+    /// Reads the pin once, keeping both the raw ADC value and the voltage it
+    /// stands for.
+    pub fn analog_reading(&mut self) -> Result<AnalogReading, TesselError> {
+        let raw = try!(self.analog_read());
+        Ok(AnalogReading::from_raw(raw))
+    }
+
+    /// Computes the prescaler index and period register for a PWM frequency,
+    /// preferring the smallest prescaler (and so the finest duty resolution).
+    fn compute_pwm_period(profile: &McuProfile, frequency: u32) -> Option<(u8, u16)> {
+        if frequency == 0 {
+            return None;
+        }
+        for (i, prescaler) in PWM_PRESCALERS.iter().enumerate() {
+            let period = profile.clock_speed / prescaler / frequency;
+            if period == 0 {
+                return None;
+            }
+            if period <= u16::max_value() as u32 {
+                return Some((i as u8, period as u16));
+            }
+        }
+        None
+    }
+
+    pub fn high(&mut self) -> Result<(), TesselError> {
+        self.output(true)
+    }
+
+    pub fn low(&mut self) -> Result<(), TesselError> {
+        self.output(false)
+    }
+
+    /// Releases the pin so it can be taken again, e.g. by another role in a
+    /// state machine. This is the same as dropping the `Pin`, for when a
+    /// scope would otherwise hold it too long.
+    pub fn release(self) {
+        drop(self);
+    }
+}
+
+/// Pins that can output PWM, for `Port::pwm_pin()`.
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PwmPinIndex {
+    Pin5 = 5,
+    Pin6 = 6,
+}
+
+/// Pins that can read analog values, for `Port::analog_pin()`.
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnalogPinIndex {
+    Pin4 = 4,
+    Pin5 = 5,
+    Pin6 = 6,
+    Pin7 = 7,
+}
+
+/// Pins that can wait for edges, for `Port::interrupt_pin()`.
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InterruptPinIndex {
+    Pin2 = 2,
+    Pin5 = 5,
+    Pin6 = 6,
+    Pin7 = 7,
+}
+
+/// Internal pull resistor settings for a `Pin`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pull {
+    /// Pull the pin low when it isn't driven.
+    Down = 0,
+    /// Pull the pin high when it isn't driven.
+    Up = 1,
+    /// Leave the pin floating.
+    None = 2,
+}
+
+/// Signal edges that `Pin::wait_for_edge()` can wait for.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edge {
+    /// The pin goes from low to high.
+    Rising = 1,
+    /// The pin goes from high to low.
+    Falling = 2,
+    /// The pin changes either way.
+    Both = 3,
+}
+
+impl<'a> Drop for Pin<'a> {
+    fn drop(&mut self) {
+        if let Some(ref locks) = self.locks {
+            locks.release(self.index);
+        }
+    }
+}
+
+/// A set of things a pin can do, as returned by `Port::pin_capabilities()`.
+/// Combine flags with `|`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PinCapabilities {
+    bits: u8,
+}
+
+impl PinCapabilities {
+    /// Reads analog values with `Pin::analog_read()`.
+    pub const ANALOG: PinCapabilities = PinCapabilities { bits: 1 << 0 };
+    /// Outputs PWM with `Pin::pwm()`.
+    pub const PWM: PinCapabilities = PinCapabilities { bits: 1 << 1 };
+    /// Reports edges to `Pin::wait_for_edge()`.
+    pub const INTERRUPT: PinCapabilities = PinCapabilities { bits: 1 << 2 };
+    /// Carries a hardware I2C signal.
+    pub const I2C: PinCapabilities = PinCapabilities { bits: 1 << 3 };
+    /// Carries a hardware SPI signal.
+    pub const SPI: PinCapabilities = PinCapabilities { bits: 1 << 4 };
+
+    /// No capabilities.
+    pub fn empty() -> PinCapabilities {
+        PinCapabilities { bits: 0 }
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Whether every capability in `other` is also in `self`.
+    pub fn contains(&self, other: PinCapabilities) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    // The capabilities of a Tessel 2 port pin.
+    fn tessel2(index: usize) -> PinCapabilities {
+        let table = [
+            (&ANALOG_PINS[..], PinCapabilities::ANALOG),
+            (&PWM_PINS[..], PinCapabilities::PWM),
+            (&INTERRUPT_PINS[..], PinCapabilities::INTERRUPT),
+            (&I2C_PINS[..], PinCapabilities::I2C),
+            (&SPI_PINS[..], PinCapabilities::SPI),
+        ];
+        table.iter()
+            .filter(|&&(pins, _)| pins.contains(&index))
+            .fold(PinCapabilities::empty(), |caps, &(_, flag)| caps | flag)
+    }
+}
+
+impl BitOr for PinCapabilities {
+    type Output = PinCapabilities;
+
+    fn bitor(self, other: PinCapabilities) -> PinCapabilities {
+        PinCapabilities { bits: self.bits | other.bits }
+    }
+}
+
+/// One sample from an analog pin.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AnalogReading {
+    /// The 12-bit ADC value.
+    pub raw: u16,
+    /// The voltage `raw` stands for, relative to the 3.3V analog reference.
+    pub voltage: f32,
+}
+
+impl AnalogReading {
+    /// Converts a raw ADC value into a reading.
+    pub fn from_raw(raw: u16) -> AnalogReading {
+        AnalogReading {
+            raw: raw,
+            voltage: raw as f32 / ANALOG_RESOLUTION as f32 * ANALOG_REFERENCE_VOLTAGE,
+        }
+    }
+}
+
+/// Whether a pin reads the line or drives it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PinMode {
+    Input,
+    Output,
+}
+
+/// An I2C slave address. Plain `u8` addresses convert to 7-bit addresses.
+///
+/// The 7-bit addresses 0x00 to 0x07 and 0x78 to 0x7F are reserved by the I2C
+/// specification, e.g. 0x00 is the general call address. `SevenBit` rejects
+/// them so a typo can't broadcast to the bus; use `Reserved` to reach them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum I2cAddress {
+    SevenBit(u8),
+    TenBit(u16),
+    Reserved(u8),
+}
+
+impl I2cAddress {
+    fn validate(self) -> Result<I2cAddress, TesselError> {
+        match self {
+            I2cAddress::SevenBit(address) if address > 0x7F => {
+                Err(TesselError::InvalidArgument("I2C address out of range."))
+            }
+            I2cAddress::SevenBit(address) if I2cAddress::is_reserved(address) => {
+                Err(TesselError::InvalidArgument("I2C address is reserved; use I2cAddress::Reserved."))
+            }
+            I2cAddress::TenBit(address) if address > 0x3FF => {
+                Err(TesselError::InvalidArgument("I2C address out of range."))
+            }
+            I2cAddress::Reserved(address) if address > 0x7F || !I2cAddress::is_reserved(address) => {
+                Err(TesselError::InvalidArgument("I2C address is not reserved."))
+            }
+            _ => Ok(self),
+        }
+    }
+
+    fn is_reserved(address: u8) -> bool {
+        address < 0x08 || address > 0x77
+    }
+}
+
+impl From<u8> for I2cAddress {
+    fn from(address: u8) -> I2cAddress {
+        I2cAddress::SevenBit(address)
+    }
+}
+
+/// Standard mode I2C bus frequency, in Hz.
+pub const I2C_STANDARD_MODE: u32 = 100_000;
+/// Fast mode I2C bus frequency, in Hz.
+pub const I2C_FAST_MODE: u32 = 400_000;
+
+// The reserved address every general call is sent to.
+const I2C_GENERAL_CALL: u8 = 0x00;
+// The general call command asking slaves to reset.
+const I2C_SOFTWARE_RESET: u8 = 0x06;
+// The most SCL pulses a stuck slave can need to finish its byte and ACK.
+const I2C_RECOVERY_PULSES: usize = 9;
+
+/// Settings for an I2C Port.
+/// # Example
+/// ```rust,no_run
+/// use tessel::{I2cConfig, Tessel, I2C_FAST_MODE};
+///
+/// let (port_a, _) = Tessel::ports().unwrap();
+/// let config = I2cConfig::default().with_frequency(Some(I2C_FAST_MODE));
+/// let (i2c, _) = port_a.i2c_with_config(config).unwrap();
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct I2cConfig {
+    /// The bus frequency in Hz.
+    pub frequency: u32,
+    /// How long a slave may stretch the clock before a read fails with
+    /// `TimedOut`, in milliseconds. `None` waits forever.
+    pub stretch_timeout_ms: Option<u16>,
+    /// How long `I2cPort::read()` waits for the coprocessor's reply before
+    /// retrying or failing with `TimedOut`. `None` waits as long as the
+    /// stretch timeout, or forever if that is `None` too.
+    pub reply_timeout: Option<Duration>,
+    /// How many more times `I2cPort::read()` sends the read again after its
+    /// reply timed out, e.g. when a bus glitch lost the reply.
+    pub retries: u8,
+}
+
+impl Default for I2cConfig {
+    fn default() -> I2cConfig {
+        I2cConfig {
+            frequency: I2C_STANDARD_MODE,
+            stretch_timeout_ms: None,
+            reply_timeout: None,
+            retries: 0,
+        }
+    }
+}
+
+impl I2cConfig {
+    /// Sets the bus frequency in Hz, or the standard mode frequency if `None`.
+    pub fn with_frequency(mut self, frequency: Option<u32>) -> I2cConfig {
+        self.frequency = frequency.unwrap_or(I2C_STANDARD_MODE);
+        self
+    }
+
+    /// Returns the bus frequency the hardware will actually run at, which can
+    /// differ from `frequency` because the baud register is quantized. This
+    /// assumes a Tessel 2; use `I2cPort::actual_frequency()` for other
+    /// profiles.
+    pub fn actual_frequency(&self) -> u32 {
+        let profile = McuProfile::tessel2();
+        I2cPort::baud_frequency(&profile, I2cPort::compute_baud(&profile, self.frequency))
+    }
+
+    /// Returns the actual frequency, or `InvalidArgument` if it differs from
+    /// the requested `frequency` by more than `tolerance` (e.g. 0.05 for 5%).
+    pub fn check_frequency(&self, tolerance: f64) -> Result<u32, TesselError> {
+        I2cPort::check_frequency(&McuProfile::tessel2(), self.frequency, tolerance)
+    }
+
+    /// Sets the clock stretching timeout in milliseconds.
+    pub fn with_stretch_timeout(mut self, stretch_timeout_ms: Option<u16>) -> I2cConfig {
+        self.stretch_timeout_ms = stretch_timeout_ms;
+        self
+    }
+
+    /// Sets how long reads wait for a reply before retrying.
+    pub fn with_reply_timeout(mut self, reply_timeout: Option<Duration>) -> I2cConfig {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    /// Sets how many times a timed out read is retried.
+    pub fn with_retries(mut self, retries: u8) -> I2cConfig {
+        self.retries = retries;
+        self
+    }
+}
+
+/// The byte order of a multi-byte register value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Endian {
+    /// Most significant byte first, as most sensors send.
+    Big,
+    /// Least significant byte first.
+    Little,
+}
+
+impl Endian {
+    // Combines bytes read in this order into a value.
+    fn assemble(self, bytes: &[u8]) -> u32 {
+        let fold = |value: u32, &byte: &u8| value << 8 | byte as u32;
+        match self {
+            Endian::Big => bytes.iter().fold(0, fold),
+            Endian::Little => bytes.iter().rev().fold(0, fold),
+        }
+    }
+}
+
+/// One step of an `I2cPort::transaction()`.
+#[derive(Debug, PartialEq)]
+pub enum I2cOp<'a> {
+    /// Write the bytes to the slave.
+    Write(&'a [u8]),
+    /// Read from the slave until the buffer is full.
+    Read(&'a mut [u8]),
+}
+
+/// An I2C Port.
+pub struct I2cPort<'a> {
+    socket: Arc<Mutex<PortSocket>>,
+    // Shared with the Port, to free it for other peripherals on drop.
+    locks: Arc<PinLocks>,
+    // Which claim on the port this is; see `PinLocks::release_mode()`.
+    claim: u64,
+    // SCL and SDA, held while I2C is enabled; taken out by free().
+    pins: Option<(Pin<'static>, Pin<'static>)>,
+    // The bus frequency in Hz.
+    frequency: u32,
+    // How long to wait for read data before giving up.
+    timeout: Option<Duration>,
+    // How many times read() is resent after timing out.
+    retries: u8,
+    // Set while a `*_no_stop()` call has left the bus held, to whether
+    // nothing but writes were sent since its START.
+    held: Option<bool>,
+    _phantom: PhantomData<&'a Port>,
+}
+
+impl<'a> fmt::Debug for I2cPort<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("I2cPort")
+            .field("frequency", &self.frequency)
+            .field("timeout", &self.timeout)
+            .field("held", &self.held.is_some())
+            .finish()
+    }
+}
+
+impl<'p> I2cPort<'p> {
+    // Builds the handle for I2C enabled by enable_socket().
+    fn new<'a>(socket: Arc<Mutex<PortSocket>>, locks: Arc<PinLocks>, claim: u64, pins: (Pin<'static>, Pin<'static>),
+               config: I2cConfig) -> I2cPort<'a> {
+        I2cPort {
+            socket: socket,
+            locks: locks,
+            claim: claim,
+            pins: Some(pins),
+            frequency: config.frequency,
+            timeout: config.reply_timeout.or(config.stretch_timeout_ms.map(|ms| Duration::from_millis(ms as u64))),
+            retries: config.retries,
+            held: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    // Enables I2C with `config` over `sock`, which the caller holds.
+    fn enable_socket(sock: &mut PortSocket, config: I2cConfig) -> Result<(), TesselError> {
+        let baud = I2cPort::compute_baud(&sock.profile(), config.frequency);
+        try!(I2cPort::enable_baud(sock, baud));
+        if let Some(ms) = config.stretch_timeout_ms {
+            try!(sock.write_command(Command::I2cTimeout(ms)));
+        }
+        Ok(())
+    }
+
+    /// Computes the baudrate as used on the Atmel SAMD21 I2C register
+    /// to set the frequency of the I2C Clock.
+    fn compute_baud(profile: &McuProfile, frequency: u32) -> u8 {
+        let clock = profile.clock_speed as f64;
+        let mut intermediate: f64 = clock / frequency as f64;
+        intermediate = intermediate - clock * profile.scl_rise_time;
+        intermediate = intermediate / profile.i2c_baud_divisor as f64 -
+                       profile.i2c_baud_offset as f64;
+
+        // Return either the intermediate value or 255
+        let low = intermediate.min(u8::max_value() as f64);
+
+        // If we have a potentially negative register value
+        // Casting as i64 because .float does not seem to work
+        if (low as i64) < u8::min_value() as i64 {
+            // Use 0 instead
+            return u8::min_value();
+        } else {
+            // Return the new register value
+            return low as u8;
+        }
+    }
+
+    /// Computes the SCL frequency produced by a baud register value; the
+    /// inverse of `compute_baud()`.
+    fn baud_frequency(profile: &McuProfile, baud: u8) -> u32 {
+        let clock = profile.clock_speed as f64;
+        let divisor = profile.i2c_baud_divisor as f64 *
+                      (baud as f64 + profile.i2c_baud_offset as f64) +
+                      clock * profile.scl_rise_time;
+        (clock / divisor).round() as u32
+    }
+
+    // Returns the frequency the baud register for `frequency` produces, or
+    // InvalidArgument if compute_baud() had to clamp it further than
+    // `tolerance` from `frequency`.
+    fn check_frequency(profile: &McuProfile, frequency: u32, tolerance: f64) -> Result<u32, TesselError> {
+        if frequency == 0 {
+            return Err(TesselError::InvalidArgument("I2C frequency must be above 0 Hz."));
+        }
+        let actual = I2cPort::baud_frequency(profile, I2cPort::compute_baud(profile, frequency));
+        let error = (actual as f64 - frequency as f64).abs() / frequency as f64;
+        if error > tolerance {
+            return Err(TesselError::InvalidArgument("I2C frequency can't be met within tolerance."));
+        }
+        Ok(actual)
+    }
+
+    /// Returns the bus frequency the hardware is actually running at.
+    pub fn actual_frequency(&self) -> u32 {
+        let profile = self.socket.lock().unwrap().profile();
+        I2cPort::baud_frequency(&profile, I2cPort::compute_baud(&profile, self.frequency))
+    }
+
+    fn enable(&mut self, baud: u8) -> Result<(), TesselError> {
+        I2cPort::enable_baud(&mut self.socket.lock().unwrap(), baud)
+    }
+
+    fn enable_baud(sock: &mut PortSocket, baud: u8) -> Result<(), TesselError> {
+        // SCL and SDA may have been left push-pull by earlier GPIO use,
+        // which would fight the other devices on the bus.
+        for &pin in I2C_PINS.iter() {
+            try!(sock.write_command(Command::GpioCfg { pin: pin as u8, mode: GPIO_CFG_OPEN_DRAIN }));
+        }
+        sock.write_command(Command::EnableI2c { baud: baud })
+    }
+
+    fn start(sock: &mut PortSocket, address: I2cAddress, read: bool) -> Result<(), TesselError> {
+        match address {
+            I2cAddress::SevenBit(address) | I2cAddress::Reserved(address) => {
+                sock.write_command(Command::Start(address << 1 | read as u8))
+            }
+            I2cAddress::TenBit(address) => {
+                // 10-bit addresses begin with 0b11110 and the top two address
+                // bits, followed by the low eight bits as a data byte.
+                let header = 0b1111_0000 | ((address >> 7) as u8 & 0b110);
+                try!(sock.write_command(Command::Start(header)));
+                try!(sock.write_command(Command::Tx(&[address as u8])));
+                if read {
+                    // Reads then repeat the header with the read bit set.
+                    try!(sock.write_command(Command::Start(header | 1)));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn tx(sock: &mut PortSocket, address: I2cAddress, write_buf: &[u8]) -> Result<(), TesselError> {
+        try!(I2cPort::start(sock, address, false));
+        // Write the command and data
+        sock.write_command(Command::Tx(write_buf))
+    }
+
+    fn rx(sock: &mut PortSocket, address: I2cAddress, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        try!(I2cPort::start(sock, address, true));
+        I2cPort::request(sock, read_buf.len())
+    }
+
+    fn request(sock: &mut PortSocket, len: usize) -> Result<(), TesselError> {
+        // Write the command and transfer length, 255 bytes at most per command
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(u8::max_value() as usize);
+            try!(sock.write_command(Command::Rx(chunk as u8)));
+            remaining -= chunk;
+        }
+        Ok(())
+    }
+
+    fn read_data(sock: &mut MutexGuard<PortSocket>, read_buf: &mut [u8], timeout: Option<Duration>) -> Result<(), TesselError> {
+        sock.with_read_timeout(timeout, |sock| {
+            // Each RX command is answered with its own DATA reply.
+            for chunk in read_buf.chunks_mut(u8::max_value() as usize) {
+                try!(sock.read_data(chunk));
+            }
+            Ok(())
+        })
+    }
+
+    // Like read_data(), but stops at the first RX the slave didn't answer and
+    // returns how many bytes arrived before it.
+    fn read_data_count(sock: &mut MutexGuard<PortSocket>, read_buf: &mut [u8], timeout: Option<Duration>) -> Result<usize, TesselError> {
+        sock.with_read_timeout(timeout, |sock| {
+            let mut count = 0;
+            let chunks = read_buf.chunks_mut(u8::max_value() as usize);
+            let total = chunks.len();
+            for (i, chunk) in chunks.enumerate() {
+                if !try!(sock.read_data_or_nack(chunk)) {
+                    // Each remaining RX is answered with its own NACK.
+                    for _ in i + 1..total {
+                        try!(sock.read_data_or_nack(&mut []));
+                    }
+                    break;
+                }
+                count += chunk.len();
+            }
+            Ok(count)
+        })
+    }
+
+    // Reads whether the slave acknowledged a write, failing with Nack if not.
+    fn read_ack(sock: &mut MutexGuard<PortSocket>) -> Result<(), TesselError> {
+        match try!(sock.read_reply()) {
+            x if x == reply::ACK.0 => Ok(()),
+            x if x == reply::NACK.0 => Err(TesselError::Nack),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    fn stop(sock: &mut PortSocket) -> Result<(), TesselError> {
+        // Tell I2C to send STOP condition
+        sock.write_command(Command::Stop)
+    }
+
+    // Runs `f`, which writes a transaction from its START on. If it fails
+    // after some of it was sent, a STOP is sent on a best-effort basis, so
+    // the bus isn't left held and the next transaction starts clean. If
+    // nothing was sent, e.g. a batched write failed as a whole, no START
+    // reached the bus and there is nothing to stop.
+    fn stop_on_error<T, F>(sock: &mut PortSocket, f: F) -> Result<T, TesselError>
+        where F: FnOnce(&mut PortSocket) -> Result<T, TesselError>
+    {
+        let sent = sock.bytes_sent();
+        let result = f(sock);
+        if result.is_err() && sock.bytes_sent() != sent {
+            let _ = I2cPort::stop(sock);
+        }
+        result
+    }
+
+    pub fn set_frequency(&mut self, frequency: u32) -> Result<(), TesselError> {
+        let profile = self.socket.lock().unwrap().profile();
+        try!(self.enable(I2cPort::compute_baud(&profile, frequency)));
+        self.frequency = frequency;
+        Ok(())
+    }
+
+    pub fn send<A: Into<I2cAddress>>(&mut self, address: A, write_buf: &[u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        // A transaction held open by read_no_stop() isn't answered with ACK.
+        let expect_ack = self.held.take() != Some(false);
+        let mut sock = self.socket.lock().unwrap();
+        try!(I2cPort::stop_on_error(&mut sock, |sock| sock.batch(|sock| {
+            try!(I2cPort::tx(sock, address, write_buf));
+            I2cPort::stop(sock)
+        })));
+
+        if expect_ack {
+            try!(I2cPort::read_ack(&mut sock));
+        }
+        Ok(())
+    }
+
+    /// Writes the buffers back to back as one write, e.g. a register address
+    /// and a separately owned block of data, so the caller doesn't have to
+    /// join them. They are still copied into the encoded command (which is
+    /// kept so it can be resent after a reconnect), so this doesn't save an
+    /// allocation over `send()`.
+    pub fn send_gather<A: Into<I2cAddress>>(&mut self, address: A, bufs: &[&[u8]]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        let expect_ack = self.held.take() != Some(false);
+        let mut sock = self.socket.lock().unwrap();
+        try!(I2cPort::stop_on_error(&mut sock, |sock| sock.batch(|sock| {
+            try!(I2cPort::start(sock, address, false));
+            try!(sock.write_command(Command::TxGather(bufs)));
+            I2cPort::stop(sock)
+        })));
+
+        if expect_ack {
+            try!(I2cPort::read_ack(&mut sock));
+        }
+        Ok(())
+    }
+
+    /// Writes `write_buf` to the general call address (0x00), which every
+    /// slave that supports general calls listens to. Fails with `Nack` if none
+    /// of them acknowledged.
+    pub fn general_call(&mut self, write_buf: &[u8]) -> Result<(), TesselError> {
+        self.send(I2cAddress::Reserved(I2C_GENERAL_CALL), write_buf)
+    }
+
+    /// Sends the general call software reset (0x06), which makes supporting
+    /// slaves reset and reload their address.
+    pub fn software_reset(&mut self) -> Result<(), TesselError> {
+        self.general_call(&[I2C_SOFTWARE_RESET])
+    }
+
+    /// Frees a bus a slave is holding SDA low on, e.g. after the host was
+    /// reset partway through a read: disables I2C, clocks SCL by hand until
+    /// the slave releases SDA (nine pulses at most), sends STOP, and enables
+    /// I2C again. I2C is re-enabled however recovery ends, so the port stays
+    /// usable; it still fails if SDA stays low or the pins couldn't be driven.
+    pub fn recover_bus(&mut self) -> Result<(), TesselError> {
+        let half_period = Duration::new(0, 500_000_000 / self.frequency.clamp(1, 500_000_000));
+        let released = {
+            let mut sock = self.socket.lock().unwrap();
+            self.held = None;
+            I2cPort::clock_bus_free(&mut sock, half_period)
+        };
+
+        let profile = self.socket.lock().unwrap().profile();
+        let enabled = self.enable(I2cPort::compute_baud(&profile, self.frequency));
+        let released = try!(released);
+        try!(enabled);
+        if !released {
+            return Err(TesselError::Io(io::Error::new(io::ErrorKind::Other, "I2C slave is still holding SDA low.")));
+        }
+        Ok(())
+    }
+
+    // Disables I2C and bit-bangs recover_bus(), returning whether SDA is
+    // released at the end. Leaves I2C disabled, even when it fails.
+    fn clock_bus_free(sock: &mut PortSocket, half_period: Duration) -> Result<bool, TesselError> {
+        let (scl, sda) = (I2C_PINS[0], I2C_PINS[1]);
+        try!(sock.write_command(Command::DisableI2c));
+        for &pin in I2C_PINS.iter() {
+            try!(sock.write_command(Command::GpioCfg { pin: pin as u8, mode: GPIO_CFG_OPEN_DRAIN }));
+            try!(sock.write_command(Command::GpioHigh(pin as u8)));
+        }
+
+        for _ in 0..I2C_RECOVERY_PULSES {
+            if try!(Pin::read_raw_index(sock, sda)) {
+                break;
+            }
+            try!(sock.write_command(Command::GpioLow(scl as u8)));
+            thread::sleep(half_period);
+            try!(sock.write_command(Command::GpioHigh(scl as u8)));
+            thread::sleep(half_period);
+        }
+
+        // STOP: SDA rises while SCL is high.
+        try!(sock.write_command(Command::GpioLow(scl as u8)));
+        try!(sock.write_command(Command::GpioLow(sda as u8)));
+        thread::sleep(half_period);
+        try!(sock.write_command(Command::GpioHigh(scl as u8)));
+        thread::sleep(half_period);
+        try!(sock.write_command(Command::GpioHigh(sda as u8)));
+        Pin::read_raw_index(sock, sda)
+    }
+
+    /// Probes the non-reserved 7-bit addresses (0x08 to 0x77) with empty
+    /// writes, returning the addresses that acknowledged.
+    pub fn scan(&mut self) -> Result<Vec<u8>, TesselError> {
+        self.held = None;
+        // Hold the socket for the whole scan so no other transfer interleaves.
+        let mut sock = self.socket.lock().unwrap();
+        let mut found = vec![];
+        for address in 0x08..0x78 {
+            try!(I2cPort::stop_on_error(&mut sock, |sock| {
+                try!(I2cPort::tx(sock, I2cAddress::SevenBit(address), &[]));
+                I2cPort::stop(sock)
+            }));
+            match I2cPort::read_ack(&mut sock) {
+                Ok(()) => found.push(address),
+                Err(TesselError::Nack) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(found)
+    }
+
+    /// Reads from the slave until `read_buf` is full. Reads of any length
+    /// work: they are requested 255 bytes at a time, and each request's
+    /// `DATA` frame is copied into its own part of `read_buf`.
+    ///
+    /// If the reply times out, the read is sent again up to the configured
+    /// number of retries before `TimedOut` is returned.
+    pub fn read<A: Into<I2cAddress>>(&mut self, address: A, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        self.held = None;
+        let mut sock = self.socket.lock().unwrap();
+        let mut attempts = 0;
+        loop {
+            // Anything left over from an aborted transfer (or a reply that
+            // arrived after the last attempt gave up) would be mistaken for
+            // this read's reply.
+            try!(sock.drain());
+            try!(I2cPort::stop_on_error(&mut sock, |sock| {
+                try!(I2cPort::rx(sock, address, read_buf));
+                I2cPort::stop(sock)
+            }));
+
+            match I2cPort::read_data(&mut sock, read_buf, self.timeout) {
+                Err(TesselError::TimedOut) if attempts < self.retries => attempts += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Like `read()`, but for devices that may have less to send than
+    /// `read_buf` holds: returns how many bytes were received before the
+    /// slave stopped acknowledging, which is 0 if it ignored its address.
+    /// The rest of `read_buf` is left as it was. The count is a multiple of
+    /// 255 (the bytes per RX command) unless the whole buffer was filled.
+    pub fn read_partial<A: Into<I2cAddress>>(&mut self, address: A, read_buf: &mut [u8]) -> Result<usize, TesselError> {
+        let address = try!(address.into().validate());
+        self.held = None;
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.drain());
+        try!(I2cPort::stop_on_error(&mut sock, |sock| {
+            try!(I2cPort::rx(sock, address, read_buf));
+            I2cPort::stop(sock)
+        }));
+
+        I2cPort::read_data_count(&mut sock, read_buf, self.timeout)
+    }
+
+    /// Like `transfer()`, but returns how many bytes were read, as
+    /// `read_partial()` does.
+    pub fn transfer_partial<A: Into<I2cAddress>>(&mut self, address: A, write_buf: &[u8], read_buf: &mut [u8]) -> Result<usize, TesselError> {
+        let address = try!(address.into().validate());
+        self.held = None;
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.drain());
+        try!(I2cPort::stop_on_error(&mut sock, |sock| {
+            try!(I2cPort::tx(sock, address, write_buf));
+            try!(I2cPort::rx(sock, address, read_buf));
+            I2cPort::stop(sock)
+        }));
+
+        I2cPort::read_data_count(&mut sock, read_buf, self.timeout)
+    }
+
+    /// Writes `write_buf`, then reads into `read_buf` after a repeated START.
+    /// The first START carries the write bit (`address << 1`) and the second
+    /// the read bit (`address << 1 | 1`).
+    pub fn transfer<A: Into<I2cAddress>>(&mut self, address: A, write_buf: &[u8], read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        self.held = None;
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.drain());
+        try!(I2cPort::stop_on_error(&mut sock, |sock| {
+            try!(I2cPort::tx(sock, address, write_buf));
+            try!(I2cPort::rx(sock, address, read_buf));
+            I2cPort::stop(sock)
+        }));
+
+        I2cPort::read_data(&mut sock, read_buf, self.timeout)
+    }
+
+    /// Reads a single byte, e.g. a status byte.
+    pub fn read_byte<A: Into<I2cAddress>>(&mut self, address: A) -> Result<u8, TesselError> {
+        let mut buf = [0];
+        try!(self.read(address, &mut buf));
+        Ok(buf[0])
+    }
+
+    /// Writes a single byte, e.g. a command code.
+    pub fn write_byte<A: Into<I2cAddress>>(&mut self, address: A, byte: u8) -> Result<(), TesselError> {
+        self.send(address, &[byte])
+    }
+
+    /// Writes `data` to the slave's registers, starting at `reg`.
+    pub fn write_register<A: Into<I2cAddress>>(&mut self, address: A, reg: u8, data: &[u8]) -> Result<(), TesselError> {
+        let mut write_buf = Vec::with_capacity(data.len() + 1);
+        write_buf.push(reg);
+        write_buf.extend_from_slice(data);
+        self.send(address, &write_buf)
+    }
+
+    /// Reads the slave's registers, starting at `reg`, until `read_buf` is
+    /// full. Sends the register address and reads with a repeated START.
+    pub fn read_register<A: Into<I2cAddress>>(&mut self, address: A, reg: u8, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        self.transfer(address, &[reg], read_buf)
+    }
+
+    /// Reads a 16-bit value from the registers starting at `reg`, in the
+    /// given byte order.
+    pub fn read_u16_register<A: Into<I2cAddress>>(&mut self, address: A, reg: u8, endian: Endian) -> Result<u16, TesselError> {
+        let mut buf = [0; 2];
+        try!(self.read_register(address, reg, &mut buf));
+        Ok(endian.assemble(&buf) as u16)
+    }
+
+    /// Reads a 32-bit value from the registers starting at `reg`, in the
+    /// given byte order.
+    pub fn read_u32_register<A: Into<I2cAddress>>(&mut self, address: A, reg: u8, endian: Endian) -> Result<u32, TesselError> {
+        let mut buf = [0; 4];
+        try!(self.read_register(address, reg, &mut buf));
+        Ok(endian.assemble(&buf))
+    }
+
+    /// Like `write_register()`, for slaves with 16-bit register addresses
+    /// such as larger EEPROMs. The address is sent MSB first.
+    pub fn write_register16<A: Into<I2cAddress>>(&mut self, address: A, reg: u16, data: &[u8]) -> Result<(), TesselError> {
+        self.send_gather(address, &[&[(reg >> 8) as u8, reg as u8], data])
+    }
+
+    /// Like `read_register()`, for slaves with 16-bit register addresses.
+    /// The address is sent MSB first.
+    pub fn read_from_register16<A: Into<I2cAddress>>(&mut self, address: A, reg: u16, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        self.transfer(address, &[(reg >> 8) as u8, reg as u8], read_buf)
+    }
+
+    /// Runs the operations in order as a single transaction: one START, a
+    /// repeated START wherever the direction changes, and one STOP at the end.
+    pub fn transaction<A: Into<I2cAddress>>(&mut self, address: A, ops: &mut [I2cOp]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        self.held = None;
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.drain());
+        try!(I2cPort::stop_on_error(&mut sock, |sock| {
+            let mut reading = None;
+            for op in ops.iter() {
+                match *op {
+                    I2cOp::Write(write_buf) => {
+                        if reading != Some(false) {
+                            try!(I2cPort::start(sock, address, false));
+                            reading = Some(false);
+                        }
+                        try!(sock.write_command(Command::Tx(write_buf)));
+                    }
+                    I2cOp::Read(ref read_buf) => {
+                        if reading != Some(true) {
+                            try!(I2cPort::start(sock, address, true));
+                            reading = Some(true);
+                        }
+                        try!(I2cPort::request(sock, read_buf.len()));
+                    }
+                }
+            }
+            I2cPort::stop(sock)
+        }));
+
+        for op in ops.iter_mut() {
+            if let I2cOp::Read(ref mut read_buf) = *op {
+                try!(I2cPort::read_data(&mut sock, read_buf, self.timeout));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `send()`, but leaves the bus held instead of sending STOP, so the
+    /// next call starts with a repeated START, e.g. for multi-phase
+    /// protocols `transaction()` can't express. Whether the slave
+    /// acknowledged is only reported by the call that finally sends STOP,
+    /// such as `send()` or `send_stop()`.
+    ///
+    /// The socket isn't locked between calls, so another user of the port
+    /// could interleave commands in the held transaction.
+    pub fn send_no_stop<A: Into<I2cAddress>>(&mut self, address: A, write_buf: &[u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        let mut sock = self.socket.lock().unwrap();
+        if self.held.is_none() {
+            try!(sock.drain());
+        }
+        if let Err(err) = I2cPort::stop_on_error(&mut sock, |sock| I2cPort::tx(sock, address, write_buf)) {
+            self.held = None;
+            return Err(err);
+        }
+        self.held = Some(self.held.unwrap_or(true));
+        Ok(())
+    }
+
+    /// Like `read()`, but leaves the bus held instead of sending STOP, as
+    /// `send_no_stop()` does.
+    pub fn read_no_stop<A: Into<I2cAddress>>(&mut self, address: A, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        let mut sock = self.socket.lock().unwrap();
+        if self.held.is_none() {
+            try!(sock.drain());
+        }
+        if let Err(err) = I2cPort::stop_on_error(&mut sock, |sock| I2cPort::rx(sock, address, read_buf)) {
+            self.held = None;
+            return Err(err);
+        }
+        self.held = Some(false);
+
+        I2cPort::read_data(&mut sock, read_buf, self.timeout)
+    }
+
+    /// Sends STOP to end a transaction held by `send_no_stop()` or
+    /// `read_no_stop()`. Fails with `Nack` if it only wrote and the slave
+    /// didn't acknowledge. Does nothing if the bus isn't held.
+    pub fn send_stop(&mut self) -> Result<(), TesselError> {
+        let expect_ack = match self.held.take() {
+            Some(only_writes) => only_writes,
+            None => return Ok(()),
+        };
+        let mut sock = self.socket.lock().unwrap();
+        try!(I2cPort::stop(&mut sock));
+
+        if expect_ack {
+            try!(I2cPort::read_ack(&mut sock));
+        }
+        Ok(())
+    }
+
+    /// Disables I2C and returns SCL (pin 0) and SDA (pin 1) as GPIO pins,
+    /// for switching the port to another role. The pins stay held until
+    /// they are dropped.
+    pub fn free(mut self) -> (Pin<'p>, Pin<'p>) {
+        let (scl, sda) = self.pins.take().unwrap();
+        // Dropping disables I2C.
+        drop(self);
+        (scl, sda)
+    }
+}
+
+impl<'p> Drop for I2cPort<'p> {
+    fn drop(&mut self) {
+        // Best effort: the socket may already be gone.
+        let sock = self.socket.lock();
+        if self.locks.release_mode(self.claim) {
+            if let Ok(mut sock) = sock {
+                let _ = sock.write_command(Command::DisableI2c);
+            }
+        }
+    }
+}
+
+/// An I2C master bit-banged over two GPIO pins, created by
+/// `Port::soft_i2c()`. It has the same `send()`, `read()` and `transfer()`
+/// methods as `I2cPort`.
+///
+/// Both pins are open-drain with pull-ups, so a high level is the line
+/// released, and slaves may stretch the clock by holding SCL low. Pins 0 and
+/// 1 have no internal pull-ups, so need external ones.
+#[derive(Debug)]
+pub struct SoftI2c<'a> {
+    scl: Pin<'a>,
+    sda: Pin<'a>,
+    // Half an SCL period.
+    delay: Duration,
+    // How long a slave may hold SCL low before `TimedOut`.
+    stretch_timeout: Duration,
+}
+
+impl<'a> SoftI2c<'a> {
+    fn new<'b>(mut scl: Pin<'b>, mut sda: Pin<'b>, frequency: u32) -> Result<SoftI2c<'b>, TesselError> {
+        for pin in [&mut scl, &mut sda].iter_mut() {
+            {
+                let mut sock = pin.socket.lock().unwrap();
+                try!(sock.write_command(Command::GpioCfg { pin: pin.index as u8, mode: GPIO_CFG_OPEN_DRAIN }));
+            }
+            if PULL_PINS.contains(&pin.index) {
+                try!(pin.set_pull(Pull::Up));
+            }
+            // Release the line.
+            try!(pin.write(true));
+        }
+
+        Ok(SoftI2c {
+            scl: scl,
+            sda: sda,
+            delay: Duration::new(0, 500_000_000 / frequency.min(500_000_000)),
+            stretch_timeout: Duration::from_secs(1),
+        })
+    }
+
+    /// Sets how long a slave may stretch the clock before the transfer fails
+    /// with `TimedOut`; one second by default.
+    pub fn set_stretch_timeout(&mut self, timeout: Duration) {
+        self.stretch_timeout = timeout;
+    }
+
+    pub fn send<A: Into<I2cAddress>>(&mut self, address: A, write_buf: &[u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        try!(self.start());
+        let result = self.write_address(address, false).and_then(|_| self.write_bytes(write_buf));
+        self.finish(result)
+    }
+
+    pub fn read<A: Into<I2cAddress>>(&mut self, address: A, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        try!(self.start());
+        let result = self.write_address(address, true).and_then(|_| self.read_bytes(read_buf));
+        self.finish(result)
+    }
+
+    /// Writes `write_buf`, then reads into `read_buf` after a repeated START.
+    pub fn transfer<A: Into<I2cAddress>>(&mut self, address: A, write_buf: &[u8], read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let address = try!(address.into().validate());
+        try!(self.start());
+        let result = self.write_address(address, false)
+            .and_then(|_| self.write_bytes(write_buf))
+            .and_then(|_| self.start())
+            .and_then(|_| self.write_address(address, true))
+            .and_then(|_| self.read_bytes(read_buf));
+        self.finish(result)
+    }
+
+    /// Releases the bus, returning the SCL and SDA pins.
+    pub fn free(self) -> (Pin<'a>, Pin<'a>) {
+        (self.scl, self.sda)
+    }
+
+    // Sends STOP whether or not the transfer succeeded, so the bus is left
+    // idle, and returns the transfer's error first.
+    fn finish(&mut self, result: Result<(), TesselError>) -> Result<(), TesselError> {
+        let stopped = self.stop();
+        result.and(stopped)
+    }
+
+    // Sends a START, or a repeated START in the middle of a transfer.
+    fn start(&mut self) -> Result<(), TesselError> {
+        try!(self.sda.write(true));
+        try!(self.scl_release());
+        thread::sleep(self.delay);
+        try!(self.sda.write(false));
+        thread::sleep(self.delay);
+        self.scl.write(false)
+    }
+
+    fn stop(&mut self) -> Result<(), TesselError> {
+        try!(self.sda.write(false));
+        thread::sleep(self.delay);
+        try!(self.scl_release());
+        thread::sleep(self.delay);
+        try!(self.sda.write(true));
+        thread::sleep(self.delay);
+        Ok(())
+    }
+
+    // Releases SCL and waits for it to go high, in case a slave is
+    // stretching the clock.
+    fn scl_release(&mut self) -> Result<(), TesselError> {
+        try!(self.scl.write(true));
+        let started = Instant::now();
+        while !try!(self.scl.read_raw()) {
+            if started.elapsed() >= self.stretch_timeout {
+                return Err(TesselError::TimedOut);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), TesselError> {
+        try!(self.sda.write(bit));
+        thread::sleep(self.delay);
+        try!(self.scl_release());
+        thread::sleep(self.delay);
+        self.scl.write(false)
+    }
+
+    fn read_bit(&mut self) -> Result<bool, TesselError> {
+        try!(self.sda.write(true));
+        thread::sleep(self.delay);
+        try!(self.scl_release());
+        let bit = try!(self.sda.read_raw());
+        thread::sleep(self.delay);
+        try!(self.scl.write(false));
+        Ok(bit)
+    }
+
+    // Writes a byte MSB first, failing with Nack if the slave doesn't pull
+    // SDA low in the ninth clock.
+    fn write_byte(&mut self, byte: u8) -> Result<(), TesselError> {
+        for i in (0..8).rev() {
+            try!(self.write_bit(byte >> i & 1 == 1));
+        }
+        match try!(self.read_bit()) {
+            false => Ok(()),
+            true => Err(TesselError::Nack),
+        }
+    }
+
+    // Reads a byte MSB first, then acknowledges it unless it is the last.
+    fn read_byte(&mut self, last: bool) -> Result<u8, TesselError> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = byte << 1 | try!(self.read_bit()) as u8;
+        }
+        try!(self.write_bit(last));
+        Ok(byte)
+    }
+
+    fn write_bytes(&mut self, write_buf: &[u8]) -> Result<(), TesselError> {
+        for &byte in write_buf {
+            try!(self.write_byte(byte));
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let len = read_buf.len();
+        for (i, byte) in read_buf.iter_mut().enumerate() {
+            *byte = try!(self.read_byte(i + 1 == len));
+        }
+        Ok(())
+    }
+
+    // Addresses the slave after a START, the same way I2cPort::start() does.
+    fn write_address(&mut self, address: I2cAddress, read: bool) -> Result<(), TesselError> {
+        match address {
+            I2cAddress::SevenBit(address) | I2cAddress::Reserved(address) => {
+                self.write_byte(address << 1 | read as u8)
+            }
+            I2cAddress::TenBit(address) => {
+                let header = 0b1111_0000 | ((address >> 7) as u8 & 0b110);
+                try!(self.write_byte(header));
+                try!(self.write_byte(address as u8));
+                if read {
+                    try!(self.start());
+                    try!(self.write_byte(header | 1));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// SPI clock polarity (CPOL) and phase (CPHA) combinations, numbered as in
+/// device datasheets. The coprocessor's mode byte is `CPOL | CPHA << 1`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpiMode {
+    /// CPOL = 0, CPHA = 0.
+    Mode0 = 0b00,
+    /// CPOL = 0, CPHA = 1.
+    Mode1 = 0b10,
+    /// CPOL = 1, CPHA = 0.
+    Mode2 = 0b01,
+    /// CPOL = 1, CPHA = 1.
+    Mode3 = 0b11,
+}
+
+/// The order bits of each SPI character are shifted out in.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOrder {
+    /// Most significant bit first, as most devices expect.
+    MsbFirst = 0,
+    /// Least significant bit first, as some shift registers and LED strips
+    /// expect.
+    LsbFirst = 1,
+}
+
+/// Settings for an SPI Port.
+/// # Example
+/// ```rust,no_run
+/// use tessel::{BitOrder, SpiConfig, SpiMode, Tessel};
+///
+/// let (port_a, _) = Tessel::ports().unwrap();
+/// let config = SpiConfig::default()
+///     .with_clock_speed(1_000_000)
+///     .with_mode(SpiMode::Mode3)
+///     .with_bit_order(BitOrder::LsbFirst);
+/// let (spi, _) = port_a.spi_with_config(config).unwrap();
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SpiConfig {
+    /// The clock speed in Hz.
+    pub clock_speed: u32,
+    /// The clock polarity and phase.
+    pub mode: SpiMode,
+    /// Whether characters are sent MSB or LSB first.
+    pub bit_order: BitOrder,
+    /// Bits per SPI character, 8 or 9.
+    pub data_size: u8,
+}
+
+impl Default for SpiConfig {
+    fn default() -> SpiConfig {
+        SpiConfig {
+            clock_speed: 2_000_000,
+            mode: SpiMode::Mode0,
+            bit_order: BitOrder::MsbFirst,
+            data_size: 8,
+        }
+    }
+}
+
+impl SpiConfig {
+    /// Sets the clock speed in Hz.
+    pub fn with_clock_speed(mut self, clock_speed: u32) -> SpiConfig {
+        self.clock_speed = clock_speed;
+        self
+    }
+
+    /// Sets the clock polarity and phase.
+    pub fn with_mode(mut self, mode: SpiMode) -> SpiConfig {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether characters are sent MSB or LSB first.
+    pub fn with_bit_order(mut self, bit_order: BitOrder) -> SpiConfig {
+        self.bit_order = bit_order;
+        self
+    }
+
+    /// Sets the bits per SPI character, 8 or 9.
+    pub fn with_data_size(mut self, data_size: u8) -> SpiConfig {
+        self.data_size = data_size;
+        self
+    }
+}
+
+/// An SPI Port.
+pub struct SpiPort<'a> {
+    socket: Arc<Mutex<PortSocket>>,
+    // Shared with the Port, to free it for other peripherals on drop.
+    locks: Arc<PinLocks>,
+    // Which claim on the port this is; see `PinLocks::release_mode()`.
+    claim: u64,
+    // SCK, MISO and MOSI, held while SPI is enabled; taken out by free().
+    pins: Option<(Pin<'static>, Pin<'static>, Pin<'static>)>,
+    clock_speed: u32,
+    mode: SpiMode,
+    bit_order: BitOrder,
+    // Bits per SPI character.
+    data_size: u8,
+    _phantom: PhantomData<&'a Port>,
+}
+
+impl<'a> fmt::Debug for SpiPort<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpiPort")
+            .field("clock_speed", &self.clock_speed)
+            .field("mode", &self.mode)
+            .field("bit_order", &self.bit_order)
+            .field("data_size", &self.data_size)
+            .finish()
+    }
+}
+
+impl<'p> SpiPort<'p> {
+    // Builds the handle for SPI enabled by enable_socket().
+    fn new<'a>(socket: Arc<Mutex<PortSocket>>, locks: Arc<PinLocks>, claim: u64,
+               pins: (Pin<'static>, Pin<'static>, Pin<'static>), config: SpiConfig) -> SpiPort<'a> {
+        SpiPort {
+            socket: socket,
+            locks: locks,
+            claim: claim,
+            pins: Some(pins),
+            clock_speed: config.clock_speed,
+            mode: config.mode,
+            bit_order: config.bit_order,
+            data_size: config.data_size,
+            _phantom: PhantomData,
+        }
+    }
+
+    // Enables SPI with `config` over `sock`, which the caller holds.
+    fn enable_socket(sock: &mut PortSocket, config: SpiConfig) -> Result<(), TesselError> {
+        if config.data_size != 8 && config.data_size != 9 {
+            return Err(TesselError::InvalidArgument("SPI data size must be 8 or 9 bits."));
+        }
+        let (freq, div) = SpiPort::compute_clock(&sock.profile(), config.clock_speed);
+        try!(sock.write_command(Command::EnableSpi { mode: config.mode as u8, freq: freq, div: div }));
+        // The coprocessor starts out at 8 bits MSB first.
+        if config.data_size != 8 {
+            try!(sock.write_command(Command::SpiDataSize(config.data_size)));
+        }
+        if config.bit_order != BitOrder::MsbFirst {
+            try!(sock.write_command(Command::SpiBitOrder(config.bit_order as u8)));
+        }
+        Ok(())
+    }
+
+    /// Computes the baud register and clock divisor used on the Atmel SAMD21
+    /// SERCOM to produce the requested SPI clock speed.
+    fn compute_clock(profile: &McuProfile, clock_speed: u32) -> (u8, u8) {
+        // The SPI clock is the MCU clock / (2 * div * (baud + 1)).
+        let clock = profile.clock_speed as f64;
+        let mut div: f64 = 1.0;
+        let mut baud: f64 = clock / (2.0 * clock_speed as f64) - 1.0;
+
+        // If the baud register can't hold the value, prescale the clock.
+        if baud > u8::max_value() as f64 {
+            div = (clock / (2.0 * 256.0 * clock_speed as f64))
+                .ceil()
+                .min(u8::max_value() as f64);
+            baud = clock / (2.0 * div * clock_speed as f64) - 1.0;
+        }
+
+        (baud.clamp(0.0, u8::max_value() as f64) as u8, div as u8)
+    }
+
+    /// Returns the SPI clock speed the hardware is actually running at.
+    pub fn actual_clock_speed(&self) -> u32 {
+        let profile = self.socket.lock().unwrap().profile();
+        let (baud, div) = SpiPort::compute_clock(&profile, self.clock_speed);
+        profile.clock_speed / (2 * div as u32 * (baud as u32 + 1))
+    }
+
+    pub fn send(&mut self, write_buf: &[u8]) -> Result<(), TesselError> {
+        let mut sock = self.socket.lock().unwrap();
+        sock.write_command(Command::Tx(write_buf))
+    }
+
+    /// Sends a large buffer, such as a display framebuffer, as a single DMA
+    /// command instead of `send()`'s 255 byte TX commands, and blocks until
+    /// the coprocessor has clocked it all out. That saves two bytes of
+    /// framing per 255 bytes sent, and the coprocessor streams the buffer
+    /// without pausing between commands. Needs firmware with `SPI_DMA`;
+    /// see `Port::firmware_version()`.
+    pub fn send_bulk(&mut self, write_buf: &[u8]) -> Result<(), TesselError> {
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::SpiDma(write_buf)));
+
+        match try!(sock.read_reply()) {
+            x if x == reply::ACK.0 => Ok(()),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    pub fn transfer(&mut self, write_buf: &[u8], read_buf: &mut [u8]) -> Result<(), TesselError> {
+        // TXRX can only carry 255 bytes at a time.
+        self.transfer_chunks(write_buf, read_buf, u8::max_value() as usize)
+    }
+
+    /// Sets the number of bits per SPI character. The SAMD21 supports 8 and
+    /// 9 bit characters; 16-bit devices work at 8 bits with `transfer_words()`.
+    pub fn set_data_size(&mut self, bits: u8) -> Result<(), TesselError> {
+        if bits != 8 && bits != 9 {
+            return Err(TesselError::InvalidArgument("SPI data size must be 8 or 9 bits."));
+        }
+
+        try!(self.socket.lock().unwrap().write_command(Command::SpiDataSize(bits)));
+        self.data_size = bits;
+        Ok(())
+    }
+
+    /// Sets whether each character is shifted out MSB or LSB first.
+    pub fn set_bit_order(&mut self, order: BitOrder) -> Result<(), TesselError> {
+        try!(self.socket.lock().unwrap().write_command(Command::SpiBitOrder(order as u8)));
+        self.bit_order = order;
+        Ok(())
+    }
+
+    /// Sends words, each as two bytes with the most significant first. At
+    /// 8 bits per character that is two characters per word, as 16-bit
+    /// devices expect; at 9 bits it is one character per word.
+    pub fn send_words(&mut self, write_buf: &[u16]) -> Result<(), TesselError> {
+        let bytes = try!(self.encode_words(write_buf));
+        let mut sock = self.socket.lock().unwrap();
+        // Keep both bytes of a word in the same TX command.
+        for chunk in bytes.chunks(u8::max_value() as usize - 1) {
+            try!(sock.write_command(Command::Tx(chunk)));
+        }
+        Ok(())
+    }
+
+    /// Like `transfer()`, with words encoded as for `send_words()`.
+    pub fn transfer_words(&mut self, write_buf: &[u16], read_buf: &mut [u16]) -> Result<(), TesselError> {
+        if write_buf.len() != read_buf.len() {
+            return Err(TesselError::InvalidArgument("SPI buffers must be the same length."));
+        }
+
+        let bytes = try!(self.encode_words(write_buf));
+        let mut read_bytes = vec![0; bytes.len()];
+        try!(self.transfer_chunks(&bytes, &mut read_bytes, u8::max_value() as usize - 1));
+
+        for (word, pair) in read_buf.iter_mut().zip(read_bytes.chunks(2)) {
+            *word = (pair[0] as u16) << 8 | pair[1] as u16;
+        }
+        Ok(())
+    }
+
+    fn encode_words(&self, words: &[u16]) -> Result<Vec<u8>, TesselError> {
+        if self.data_size == 9 && words.iter().any(|&word| word > 0x1FF) {
+            return Err(TesselError::InvalidArgument("SPI word does not fit in 9 bits."));
+        }
+
+        let mut bytes = Vec::with_capacity(words.len() * 2);
+        for &word in words {
+            bytes.push((word >> 8) as u8);
+            bytes.push(word as u8);
+        }
+        Ok(bytes)
+    }
+
+    fn transfer_chunks(&mut self, write_buf: &[u8], read_buf: &mut [u8], chunk_len: usize) -> Result<(), TesselError> {
+        if write_buf.len() != read_buf.len() {
+            return Err(TesselError::InvalidArgument("SPI buffers must be the same length."));
+        }
+
+        let mut sock = self.socket.lock().unwrap();
+        // Each TXRX chunk gets its own reply.
+        for (write, read) in write_buf.chunks(chunk_len).zip(read_buf.chunks_mut(chunk_len)) {
+            try!(sock.write_command(Command::TxRx(write)));
+            try!(sock.read_data(read));
+        }
+        Ok(())
+    }
+
+    /// Drives the chip select pin `cs` low until the returned guard is
+    /// dropped, so several slaves can share the bus.
+    pub fn chip_select<'s, 'c>(&'s mut self, cs: &'s mut Pin<'c>) -> Result<ChipSelect<'s, 'p, 'c>, TesselError> {
+        try!(cs.low());
+        Ok(ChipSelect { spi: self, cs: cs })
+    }
+
+    /// Like `transfer()`, with `cs` held low for the duration.
+    pub fn transfer_with_cs(&mut self, cs: &mut Pin, write_buf: &[u8], read_buf: &mut [u8]) -> Result<(), TesselError> {
+        let mut selected = try!(self.chip_select(cs));
+        selected.transfer(write_buf, read_buf)
+    }
+
+    /// Disables SPI and returns SCK (pin 2), MISO (pin 3) and MOSI (pin 4)
+    /// as GPIO pins, for switching the port to another role. The pins stay
+    /// held until they are dropped.
+    pub fn free(mut self) -> (Pin<'p>, Pin<'p>, Pin<'p>) {
+        let (sck, miso, mosi) = self.pins.take().unwrap();
+        // Dropping disables SPI.
+        drop(self);
+        (sck, miso, mosi)
+    }
+}
+
+impl<'p> Drop for SpiPort<'p> {
+    fn drop(&mut self) {
+        // Best effort: the socket may already be gone.
+        let sock = self.socket.lock();
+        if self.locks.release_mode(self.claim) {
+            if let Ok(mut sock) = sock {
+                let _ = sock.write_command(Command::DisableSpi);
+            }
+        }
+    }
+}
+
+/// An SPI slave selected by `SpiPort::chip_select()`. Releases the chip
+/// select pin when dropped.
+#[derive(Debug)]
+pub struct ChipSelect<'s, 'p: 's, 'c: 's> {
+    spi: &'s mut SpiPort<'p>,
+    cs: &'s mut Pin<'c>,
+}
+
+impl<'s, 'p, 'c> ChipSelect<'s, 'p, 'c> {
+    pub fn send(&mut self, write_buf: &[u8]) -> Result<(), TesselError> {
+        self.spi.send(write_buf)
+    }
+
+    pub fn transfer(&mut self, write_buf: &[u8], read_buf: &mut [u8]) -> Result<(), TesselError> {
+        self.spi.transfer(write_buf, read_buf)
+    }
+}
+
+impl<'s, 'p, 'c> Drop for ChipSelect<'s, 'p, 'c> {
+    fn drop(&mut self) {
+        // Best effort: the socket may already be gone.
+        let _ = self.cs.high();
+    }
+}
+
+/// A UART Port.
+pub struct UartPort<'a> {
+    socket: Arc<Mutex<PortSocket>>,
+    // Shared with the Port, to free it for other peripherals on drop.
+    locks: Arc<PinLocks>,
+    // Which claim on the port this is; see `PinLocks::release_mode()`.
+    claim: u64,
+    // TX and RX, held while the UART is enabled; taken out by free().
+    pins: Option<(Pin<'static>, Pin<'static>)>,
+    baud: u32,
+    _phantom: PhantomData<&'a Port>,
+}
+
+impl<'a> fmt::Debug for UartPort<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UartPort")
+            .field("baud", &self.baud)
+            .finish()
+    }
+}
+
+impl<'p> UartPort<'p> {
+    // Builds the handle for the UART enabled by enable_socket().
+    fn new<'a>(socket: Arc<Mutex<PortSocket>>, locks: Arc<PinLocks>, claim: u64, pins: (Pin<'static>, Pin<'static>),
+               baud: u32) -> UartPort<'a> {
+        UartPort {
+            socket: socket,
+            locks: locks,
+            claim: claim,
+            pins: Some(pins),
+            baud: baud,
+            _phantom: PhantomData,
+        }
+    }
+
+    // Enables the UART at `baud` over `sock`, which the caller holds.
+    fn enable_socket(sock: &mut PortSocket, baud: u32) -> Result<(), TesselError> {
+        let register = UartPort::compute_baud(&sock.profile(), baud);
+        sock.write_command(Command::EnableUart { baud: register })
+    }
+
+    /// Computes the arithmetic baud register value used on the Atmel SAMD21
+    /// SERCOM to set the UART baud rate.
+    fn compute_baud(profile: &McuProfile, baud: u32) -> u16 {
+        // BAUD = 65536 * (1 - 16 * (f_baud / f_ref))
+        let ratio = 16.0 * baud as f64 / profile.clock_speed as f64;
+        let value = 65536.0 * (1.0 - ratio);
+
+        value.clamp(0.0, u16::max_value() as f64) as u16
+    }
+
+    /// Disables the UART and returns TX (pin 5) and RX (pin 6) as GPIO
+    /// pins, for switching the port to another role. The pins stay held
+    /// until they are dropped.
+    pub fn free(mut self) -> (Pin<'p>, Pin<'p>) {
+        let (tx, rx) = self.pins.take().unwrap();
+        // Dropping disables the UART.
+        drop(self);
+        (tx, rx)
+    }
+}
+
+impl<'p> Read for UartPort<'p> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut sock = self.socket.lock().unwrap();
+        Ok(try!(sock.read_uart(buf)))
+    }
+}
+
+impl<'p> Write for UartPort<'p> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut sock = self.socket.lock().unwrap();
+        try!(sock.write_command(Command::Tx(buf)));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'p> Drop for UartPort<'p> {
+    fn drop(&mut self) {
+        // Best effort: the socket may already be gone.
+        let sock = self.socket.lock();
+        if self.locks.release_mode(self.claim) {
+            if let Ok(mut sock) = sock {
+                let _ = sock.write_command(Command::DisableUart);
+            }
+        }
+    }
+}
+
+// TODO: Figure out how to override the path secretly so the example
+// can actually be run.
+/// A LED models an LED on the Tessel board.
+/// # Example
+/// ```rust,no_run
+/// use tessel::LED;
+///
+/// let mut led = LED::new("red", "error");
+/// // LEDs are off by default.
+/// assert_eq!(false, led.read());
+/// led.on().unwrap();
+/// assert_eq!(true, led.read());
+pub struct LED {
+    // The color of the LED, e.g. "red".
+    color: &'static str,
+    // The role of the LED, e.g. "error".
+    kind: &'static str,
+    // The file object we write to in order to change state.
+    file: File,
+    // The path of that file, if known, for reading the state back.
+    path: Option<PathBuf>,
+    // The current brightness of the LED, defaults to 0 (off).
+    brightness: u8,
+    // The highest brightness the LED supports.
+    max_brightness: u8,
+}
+
+impl fmt::Debug for LED {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LED")
+            .field("color", &self.color)
+            .field("kind", &self.kind)
+            .field("brightness", &self.brightness)
+            .field("max_brightness", &self.max_brightness)
+            .finish()
+    }
+}
+
+impl LED {
+    pub fn new(color: &'static str, kind: &'static str) -> LED {
+        LED::try_new(color, kind).unwrap()
+    }
+
+    pub fn try_new(color: &'static str, kind: &'static str) -> Result<LED, TesselError> {
+        let dir = PathBuf::from(format!("/sys/devices/leds/leds/tessel:{}:{}", color, kind));
+        let max_brightness = try!(LED::read_max_brightness(&dir.join("max_brightness")));
+        LED::open(color, kind, dir.join("brightness"), max_brightness)
+    }
+
+    // with_path() drives the LED whose brightness file is at `path`, e.g. on
+    // a custom image or a host-side simulator. The maximum is read from the
+    // max_brightness file next to it, or taken to be 255 if there is none.
+    // The color and kind are empty; named() sets them.
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Result<LED, TesselError> {
+        let path = path.as_ref();
+        let max_path = path.with_file_name("max_brightness");
+        let max_brightness = match LED::read_max_brightness(&max_path) {
+            Err(TesselError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => u8::max_value(),
+            result => try!(result),
+        };
+        LED::open("", "", path.to_path_buf(), max_brightness)
+    }
+
+    // named() sets the color and kind an LED from with_path() reports.
+    pub fn named(mut self, color: &'static str, kind: &'static str) -> LED {
+        self.color = color;
+        self.kind = kind;
+        self
+    }
+
+    fn open(color: &'static str, kind: &'static str, path: PathBuf, max_brightness: u8) -> Result<LED, TesselError> {
+        // Open the file for write operations.
+        let mut led = try!(LED::new_with_file(color, kind, try!(File::create(&path)), max_brightness));
+        led.path = Some(path);
+        Ok(led)
+    }
+
+    // attach() is like try_new() for the LED's sysfs directory at `dir` (the
+    // one holding brightness and max_brightness), but keeps the LED as it is instead of
+    // turning it off, e.g. so a restarting process doesn't blink a status
+    // LED. The brightness is read back from sysfs.
+    pub fn attach<P: AsRef<Path>>(color: &'static str, kind: &'static str, dir: P) -> Result<LED, TesselError> {
+        let dir = dir.as_ref();
+        let max_brightness = try!(LED::read_max_brightness(&dir.join("max_brightness")));
+
+        // Opened without truncating, so the current value can be read back.
+        let path = dir.join("brightness");
+        let mut led = LED {
+            color: color,
+            kind: kind,
+            file: try!(OpenOptions::new().write(true).open(&path)),
+            path: Some(path),
+            brightness: 0,
+            max_brightness: max_brightness,
+        };
+        try!(led.read_hardware());
+        Ok(led)
+    }
+
+    // Reads the brightness range, which is fixed for each LED.
+    fn read_max_brightness(path: &Path) -> Result<u8, TesselError> {
+        let mut max_brightness = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut max_brightness));
+        match max_brightness.trim().parse::<u32>() {
+            Ok(value) => Ok(value.min(u8::max_value() as u32) as u8),
+            Err(_) => Err(TesselError::Io(io::Error::new(io::ErrorKind::InvalidData, "Invalid LED max_brightness."))),
+        }
+    }
+
+
+    fn new_with_file(color: &'static str, kind: &'static str, file: File, max_brightness: u8) -> Result<LED, TesselError> {
+        let mut led = LED {
+            color: color,
+            kind: kind,
+            file: file,
+            path: None,
+            brightness: 0,
+            max_brightness: max_brightness,
+        };
+
+        // Turn the LED off by default.
+        try!(led.off());
+
+        Ok(led)
+    }
+
+    // Returns the color of the LED.
+    pub fn color(&self) -> &'static str {
+        self.color
+    }
+
+    // Returns the role of the LED.
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    // Turn the LED on (same as `high`).
+    pub fn on(&mut self) -> Result<(), TesselError> {
+        self.high()
+    }
+
+    // Turn the LED off (same as `low`).
+    pub fn off(&mut self) -> Result<(), TesselError> {
+        self.low()
+    }
+
+    // Turn the LED on.
+    pub fn high(&mut self) -> Result<(), TesselError> {
+        self.write(true)
+    }
+
+    // Turn the LED off.
+    pub fn low(&mut self) -> Result<(), TesselError> {
+        self.write(false)
+    }
+
+    // Sets the LED to the opposite of its current state.
+    pub fn toggle(&mut self) -> Result<(), TesselError> {
+        let new_value = !self.read();
+        self.write(new_value)
+    }
+
+    // Blinks the LED `count` times, or forever if `count` is None. Each blink
+    // lasts `period`, half on and half off. The LED is left off afterwards.
+    pub fn blink(&mut self, period: Duration, count: Option<u32>) -> Result<(), TesselError> {
+        let half_period = period / 2;
+        let mut blinks = 0;
+        while count.map_or(true, |count| blinks < count) {
+            try!(self.on());
+            thread::sleep(half_period);
+            try!(self.off());
+            thread::sleep(half_period);
+            blinks += 1;
+        }
+        self.off()
+    }
+
+    // Turns the LED on or off for each duration in turn, e.g. to signal a
+    // status code. The LED is left off afterwards.
+    pub fn play_pattern(&mut self, pattern: &[(bool, Duration)]) -> Result<(), TesselError> {
+        for &(on, duration) in pattern {
+            try!(self.write(on));
+            thread::sleep(duration);
+        }
+        self.off()
+    }
+
+    // Blinks `text` in Morse code, with dots lasting `unit` and dashes three
+    // units. Letters, digits, and spaces are supported.
+    pub fn morse(&mut self, text: &str, unit: Duration) -> Result<(), TesselError> {
+        let pattern = try!(LED::morse_pattern(text, unit));
+        self.play_pattern(&pattern)
+    }
+
+    // Spells out `text` as on/off states, with the standard gaps: one unit
+    // between the symbols of a character, three between characters, and
+    // seven between words.
+    fn morse_pattern(text: &str, unit: Duration) -> Result<Vec<(bool, Duration)>, TesselError> {
+        let mut pattern = vec![];
+        for (i, word) in text.split_whitespace().enumerate() {
+            if i > 0 {
+                pattern.push((false, unit * 7));
+            }
+            for (j, c) in word.chars().enumerate() {
+                let code = match MORSE_CODE.iter().find(|&&(letter, _)| letter == c.to_ascii_uppercase()) {
+                    Some(&(_, code)) => code,
+                    None => return Err(TesselError::InvalidArgument("Only letters, digits, and spaces can be sent in Morse code.")),
+                };
+                if j > 0 {
+                    pattern.push((false, unit * 3));
+                }
+                for (k, symbol) in code.chars().enumerate() {
+                    if k > 0 {
+                        pattern.push((false, unit));
+                    }
+                    pattern.push((true, if symbol == '.' { unit } else { unit * 3 }));
+                }
+            }
+        }
+        Ok(pattern)
+    }
+
+    // Returns the current state of the LED.
+    pub fn read(&self) -> bool {
+        self.brightness > 0
+    }
+
+    // Re-reads the brightness from sysfs, in case another process changed it,
+    // and returns whether the LED is on.
+    pub fn read_hardware(&mut self) -> Result<bool, TesselError> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Err(TesselError::Io(io::Error::new(io::ErrorKind::NotFound, "LED has no brightness file to read."))),
+        };
+        let mut buf = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut buf));
+
+        self.brightness = match buf.trim().parse::<u32>() {
+            Ok(value) => value.min(self.max_brightness as u32) as u8,
+            Err(_) => return Err(TesselError::Io(io::Error::new(io::ErrorKind::InvalidData, "Invalid LED brightness."))),
+        };
+        Ok(self.read())
+    }
+
+    // Returns the current brightness of the LED.
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    // Returns the highest brightness the LED supports.
+    pub fn max_brightness(&self) -> u8 {
+        self.max_brightness
+    }
+
+    // Sets the brightness of the LED, clamped to its maximum brightness.
+    pub fn set_brightness(&mut self, level: u8) -> Result<(), TesselError> {
+        // Save the new value to the model.
+        self.brightness = level.min(self.max_brightness);
+
+        // Write the decimal representation to the file and return the result.
+        Ok(try!(self.file.write_all(self.brightness.to_string().as_bytes())))
+    }
+
+    // Sets the brightness as a percentage of the LED's maximum brightness,
+    // clamped to 0.0 to 100.0 and rounded to the nearest level.
+    pub fn set_percent(&mut self, pct: f32) -> Result<(), TesselError> {
+        let fraction = pct.clamp(0.0, 100.0) / 100.0;
+        let level = (fraction * self.max_brightness as f32).round() as u8;
+        self.set_brightness(level)
+    }
+
+    // Takes the LED over until the returned guard is dropped, which restores
+    // the brightness it has now, e.g. to borrow an LED another part of the
+    // app manages.
+    pub fn take<'a>(&'a mut self) -> LedGuard<'a> {
+        LedGuard {
+            brightness: self.brightness,
+            led: self,
+        }
+    }
+
+    // Steps the brightness from `from` to `to`, one level at a time, spread
+    // evenly over `duration`. The LED is left at `to`.
+    pub fn fade(&mut self, from: u8, to: u8, duration: Duration) -> Result<(), TesselError> {
+        try!(self.ramp(from, to, duration, &AtomicBool::new(false)));
+        Ok(())
+    }
+
+    // Fades the LED up and back down once every `period` on a background
+    // thread, until the returned guard is stopped or dropped.
+    pub fn breathe(mut self, period: Duration) -> Breathing {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let max = self.max_brightness;
+            while try!(self.ramp(0, max, period / 2, &stopped)) &&
+                  try!(self.ramp(max, 0, period / 2, &stopped)) {}
+            try!(self.off());
+            Ok(self)
+        });
+        Breathing { stop: stop, thread: Some(thread) }
+    }
+
+    // Helper function for fade() that gives up early once `stop` is set.
+    // Returns whether the ramp ran to completion.
+    fn ramp(&mut self, from: u8, to: u8, duration: Duration, stop: &AtomicBool) -> Result<bool, TesselError> {
+        let from = from.min(self.max_brightness);
+        let to = to.min(self.max_brightness);
+        let steps = (to as i32 - from as i32).abs() as u32;
+        if steps == 0 {
+            try!(self.set_brightness(to));
+            return Ok(!stop.load(Ordering::SeqCst));
+        }
+
+        let step_time = duration / steps;
+        for step in 0..steps + 1 {
+            if stop.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            let level = if to > from { from as u32 + step } else { from as u32 - step };
+            try!(self.set_brightness(level as u8));
+            if step < steps {
+                thread::sleep(step_time);
+            }
+        }
+        Ok(true)
+    }
+
+    // Helper function to write new state to LED filepath.
+    fn write(&mut self, new_value: bool) -> Result<(), TesselError> {
+        let level = match new_value {
+            true => self.max_brightness,
+            false => 0,
+        };
+        self.set_brightness(level)
+    }
+}
+
+/// Temporary use of an LED, from `LED::take()`. Derefs to the `LED`, and
+/// restores the LED's earlier brightness when dropped.
+#[derive(Debug)]
+pub struct LedGuard<'a> {
+    led: &'a mut LED,
+    // The brightness to restore.
+    brightness: u8,
+}
+
+impl<'a> Deref for LedGuard<'a> {
+    type Target = LED;
+
+    fn deref(&self) -> &LED {
+        self.led
+    }
+}
+
+impl<'a> DerefMut for LedGuard<'a> {
+    fn deref_mut(&mut self) -> &mut LED {
+        self.led
+    }
+}
+
+impl<'a> Drop for LedGuard<'a> {
+    fn drop(&mut self) {
+        // Best effort: there is nowhere to report a failed write.
+        let _ = self.led.set_brightness(self.brightness);
+    }
+}
+
+/// A breathing pattern running on a background thread, started by
+/// `LED::breathe()`. Dropping it stops the pattern and turns the LED off.
+#[derive(Debug)]
+pub struct Breathing {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<LED, TesselError>>>,
+}
+
+impl Breathing {
+    /// Stops the pattern, turns the LED off and hands it back.
+    pub fn stop(mut self) -> Result<LED, TesselError> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<LED, TesselError> {
+        self.stop.store(true, Ordering::SeqCst);
+        let thread = self.thread.take().expect("Breathing already stopped.");
+        match thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(TesselError::Io(io::Error::new(io::ErrorKind::Other, "LED thread panicked."))),
+        }
+    }
+}
+
+impl Drop for Breathing {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
+
+/// A Button models the button on the Tessel board.
+/// # Example
+/// ```rust,no_run
+/// use tessel::Tessel;
+///
+/// let t = Tessel::new();
+/// t.button.wait_for_press().unwrap();
+/// println!("Pressed!");
+/// ```
+#[derive(Debug)]
+pub struct Button {
+    // The sysfs file holding the button state.
+    path: PathBuf,
+}
+
+impl Button {
+    fn new<P: AsRef<Path>>(path: P) -> Button {
+        Button {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    // Returns whether the button is currently pressed.
+    pub fn read(&self) -> Result<bool, TesselError> {
+        // sysfs values must be re-read from the start, so open the file each time.
+        let mut buf = String::new();
+        try!(try!(File::open(&self.path)).read_to_string(&mut buf));
+        match buf.trim() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            _ => Err(TesselError::Io(io::Error::new(io::ErrorKind::InvalidData, "Unexpected button state."))),
+        }
+    }
+
+    // Returns whether the button is currently pressed, like read().
+    pub fn is_pressed(&self) -> Result<bool, TesselError> {
+        self.read()
+    }
+
+    // Blocks until the button is pressed. If the button is already held down,
+    // waits for it to be released and pressed again.
+    pub fn wait_for_press(&self) -> Result<(), TesselError> {
+        self.wait_for_press_debounced(Duration::from_millis(0))
+    }
+
+    // Like wait_for_press(), but the button must stay released, and then stay
+    // pressed, for `debounce` before the press counts.
+    pub fn wait_for_press_debounced(&self, debounce: Duration) -> Result<(), TesselError> {
+        try!(self.wait_for_stable(false, debounce));
+        self.wait_for_stable(true, debounce)
+    }
+
+    // Blocks until the button has read `pressed` continuously for `debounce`.
+    fn wait_for_stable(&self, pressed: bool, debounce: Duration) -> Result<(), TesselError> {
+        let mut stable_since = None;
+        loop {
+            if try!(self.read()) == pressed {
+                let since = *stable_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= debounce {
+                    return Ok(());
+                }
+            } else {
+                stable_since = None;
+            }
+            thread::sleep(Duration::from_millis(BUTTON_POLL_INTERVAL_MS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use protocol::{PortSocket, SocketTransport};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::{env, fs, process};
+    use unix_socket::{UnixListener, UnixStream};
+
+    // Replays canned replies and records every byte written to it.
+    struct MockTransport {
+        replies: Cursor<Vec<u8>>,
+        written: Arc<Mutex<Vec<u8>>>,
+        nonblocking: bool,
+    }
+
+    impl Read for MockTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // The replies answer commands that haven't been sent yet, so
+            // none of them are waiting to be read without blocking.
+            if self.nonblocking {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "No replies pending."));
+            }
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for MockTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SocketTransport for MockTransport {
+        fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+            self.nonblocking = nonblocking;
+            Ok(())
+        }
+    }
+
+    // Records written bytes like MockTransport, but fails every write that
+    // starts with `fail_on`.
+    struct FailingTransport {
+        written: Arc<Mutex<Vec<u8>>>,
+        fail_on: u8,
+    }
+
+    impl Read for FailingTransport {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for FailingTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.first() == Some(&self.fail_on) {
+                return Err(io::Error::new(io::ErrorKind::Other, "Write failed."));
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SocketTransport for FailingTransport {}
+
+    // Returns a port whose writes starting with `fail_on` fail, and the bytes
+    // the others wrote.
+    fn failing_transport_port(fail_on: u8) -> (Port, Arc<Mutex<Vec<u8>>>) {
+        let written = Arc::new(Mutex::new(vec![]));
+        let transport = FailingTransport { written: written.clone(), fail_on: fail_on };
+        (Port::new_with_socket(PortSocket::from_transport(Box::new(transport))), written)
+    }
+
+    // Returns a port backed by a MockTransport, and the bytes it writes.
+    fn mock_transport_port(replies: &[u8]) -> (Port, Arc<Mutex<Vec<u8>>>) {
+        let written = Arc::new(Mutex::new(vec![]));
+        let transport = MockTransport {
+            replies: Cursor::new(replies.to_vec()),
+            written: written.clone(),
+            nonblocking: false,
+        };
+        (Port::new_with_socket(PortSocket::from_transport(Box::new(transport))), written)
+    }
+
+    // Returns a port connected to an in-memory stand-in for the port daemon.
+    fn mock_port() -> (Port, UnixStream) {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        (Port::new_with_socket(PortSocket::from_stream(client)), daemon)
+    }
+
+    // The commands i2c_with_frequency(frequency) sends to enable I2C.
+    fn i2c_enable_prefix(frequency: u32) -> Vec<u8> {
+        vec![0x06, 0x10, 0x06, 0x11, 0x0C, I2cPort::compute_baud(&McuProfile::tessel2(), frequency)]
+    }
+
+    // Reads every byte the port wrote before it was dropped.
+    fn written_bytes(mut daemon: UnixStream) -> Vec<u8> {
+        let mut bytes = vec![];
+        daemon.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn led_writes_to_file() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        // The tmpfile handle can be reused as long as LED gets its own
+        // clone of the handle, and we are diligent about seeking.
+        // This avoids needing to figure out where the tmpfile is in order
+        // to open more handles.
+        let mut led = LED::new_with_file("red", "error", tmpfile.try_clone().unwrap(), 1).unwrap();
+        let mut buf = String::new();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        assert_eq!("0", buf);
+        led.on().unwrap();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        // b'1' is written as 001 into the file.
+        assert_eq!("001", buf);
+    }
+
+    #[test]
+    fn led_opens_custom_path() {
+        let dir = env::temp_dir().join(format!("tessel-led-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::File::create(dir.join("max_brightness")).unwrap().write_all(b"255\n").unwrap();
+
+        let mut led = LED::with_path(dir.join("brightness")).unwrap().named("green", "user1");
+        assert_eq!(255, led.max_brightness());
+        assert_eq!("green", led.color());
+        led.on().unwrap();
+        let mut buf = String::new();
+        std::fs::File::open(dir.join("brightness")).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!("0255", buf);
+
+        // Another process dims the LED.
+        std::fs::File::create(dir.join("brightness")).unwrap().write_all(b"128\n").unwrap();
+        assert_eq!(255, led.brightness());
+        assert!(led.read_hardware().unwrap());
+        assert_eq!(128, led.brightness());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn led_with_path_defaults_max_brightness() {
+        let dir = env::temp_dir().join(format!("tessel-led-max-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("max_brightness")).unwrap().write_all(b"100\n").unwrap();
+        assert_eq!(100, LED::with_path(dir.join("brightness")).unwrap().max_brightness());
+
+        // Without max_brightness, the full byte range is used.
+        fs::remove_file(dir.join("max_brightness")).unwrap();
+        let led = LED::with_path(dir.join("brightness")).unwrap();
+        assert_eq!(255, led.max_brightness());
+        assert_eq!("", led.color());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn led_attach_keeps_current_state() {
+        let dir = env::temp_dir().join(format!("tessel-led-attach-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("max_brightness")).unwrap().write_all(b"255\n").unwrap();
+        fs::File::create(dir.join("brightness")).unwrap().write_all(b"77\n").unwrap();
+
+        let led = LED::attach("blue", "wlan", &dir).unwrap();
+        assert_eq!(77, led.brightness());
+        let mut buf = String::new();
+        fs::File::open(dir.join("brightness")).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!("77\n", buf);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn led_brightness_is_clamped() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let mut led = LED::new_with_file("red", "error", tmpfile.try_clone().unwrap(), 100).unwrap();
+        led.set_brightness(50).unwrap();
+        assert_eq!(50, led.brightness());
+        led.set_brightness(200).unwrap();
+        assert_eq!(100, led.brightness());
+        led.off().unwrap();
+        assert_eq!(false, led.read());
+        led.on().unwrap();
+        assert_eq!(true, led.read());
+
+        let mut buf = String::new();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        assert_eq!("0501000100", buf);
+    }
+
+    #[test]
+    fn led_blink_ends_off() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let mut led = LED::new_with_file("red", "error", tmpfile.try_clone().unwrap(), 1).unwrap();
+        led.blink(Duration::from_millis(0), Some(2)).unwrap();
+        assert_eq!(false, led.read());
+
+        let mut buf = String::new();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        assert_eq!("010100", buf);
+    }
+
+    #[test]
+    fn led_plays_patterns() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let mut led = LED::new_with_file("red", "error", tmpfile.try_clone().unwrap(), 1).unwrap();
+        let zero = Duration::from_millis(0);
+        led.play_pattern(&[(true, zero), (true, zero), (false, zero), (true, zero)]).unwrap();
+        assert_eq!(false, led.read());
+        led.morse("SOS", zero).unwrap();
+        assert!(matches!(led.morse("S?S", zero), Err(TesselError::InvalidArgument(_))));
+
+        let mut buf = String::new();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        // The LED starts off, and a rejected message plays nothing.
+        assert_eq!("0".to_string() + "11010" + "101010101010101010", buf);
+
+        let unit = Duration::from_millis(1);
+        assert_eq!(vec![(true, unit), (false, unit), (true, unit * 3), (false, unit * 7), (true, unit * 3)],
+                   LED::morse_pattern("a t", unit).unwrap());
+    }
+
+    #[test]
+    fn led_set_percent_scales_to_max_brightness() {
+        let mut led = LED::new_with_file("red", "error", tempfile::tempfile().unwrap(), 255).unwrap();
+        led.set_percent(50.0).unwrap();
+        assert_eq!(128, led.brightness());
+        led.set_percent(150.0).unwrap();
+        assert_eq!(255, led.brightness());
+        led.set_percent(-5.0).unwrap();
+        assert_eq!(0, led.brightness());
+
+        let mut led = LED::new_with_file("red", "error", tempfile::tempfile().unwrap(), 1).unwrap();
+        led.set_percent(40.0).unwrap();
+        assert_eq!(false, led.read());
+        led.set_percent(60.0).unwrap();
+        assert_eq!(true, led.read());
+    }
+
+    #[test]
+    fn led_fade_steps_each_level() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let mut led = LED::new_with_file("red", "error", tmpfile.try_clone().unwrap(), 100).unwrap();
+        led.fade(2, 5, Duration::from_millis(3)).unwrap();
+        assert_eq!(5, led.brightness());
+        led.fade(200, 98, Duration::from_millis(0)).unwrap();
+        assert_eq!(98, led.brightness());
+
+        let mut buf = String::new();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        // Levels above max_brightness are clamped.
+        assert_eq!("023451009998", buf);
+    }
+
+    #[test]
+    fn led_guard_restores_brightness() {
+        let mut tmpfile = tempfile::tempfile().unwrap();
+        let mut led = LED::new_with_file("red", "error", tmpfile.try_clone().unwrap(), 100).unwrap();
+        led.set_brightness(40).unwrap();
+        {
+            let mut guard = led.take();
+            guard.on().unwrap();
+            assert_eq!(100, guard.brightness());
+        }
+        assert_eq!(40, led.brightness());
+
+        let mut buf = String::new();
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.read_to_string(&mut buf).unwrap();
+        assert_eq!("04010040", buf);
+    }
+
+    #[test]
+    fn led_breathe_stops_off() {
+        let tmpfile = tempfile::tempfile().unwrap();
+        let led = LED::new_with_file("red", "error", tmpfile, 4).unwrap();
+        let breathing = led.breathe(Duration::from_millis(8));
+        thread::sleep(Duration::from_millis(20));
+        let led = breathing.stop().unwrap();
+        assert_eq!(0, led.brightness());
+    }
+
+    #[test]
+    fn tessel_builder_connects_only_enabled_ports() {
+        let path = env::temp_dir().join(format!("tessel-builder-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let t = TesselBuilder::new().enable_port_a().port_a_path(&path).leds(false).build().unwrap();
+        assert_eq!(Some(path.clone()), t.port_a.as_ref().unwrap().socket_path());
+        assert!(t.port_b.is_none());
+        assert!(t.led.is_empty());
+
+        // Port B's daemon isn't running.
+        let missing = path.with_extension("missing");
+        let result = TesselBuilder::new().enable_port_b().port_b_path(&missing).leds(false).build();
+        assert!(result.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pin_set_mode_switches_direction() {
+        let (port, written) = mock_transport_port(&[]);
+        let mut pin = port.pin(3).unwrap();
+        pin.set_mode(PinMode::Output).unwrap();
+        pin.set_mode(PinMode::Input).unwrap();
+        assert_eq!(vec![0x28, 3, 0x16, 3], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn pin_pulse_is_timed_by_the_coprocessor() {
+        let (port, written) = mock_transport_port(&[reply::ACK.0]);
+        let mut pin = port.pin(2).unwrap();
+        pin.pulse(true, Duration::from_micros(10)).unwrap();
+        assert_eq!(Some(false), pin.last_written());
+        assert!(matches!(pin.pulse(true, Duration::from_nanos(999)), Err(TesselError::InvalidArgument(_))));
+
+        assert_eq!(vec![0x22, 0x12, 0, 0, 0, 10], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn pin_counts_edges_on_the_coprocessor() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x10, 0x27, 0, 0,
+                                                    reply::DATA.0, 0x05, 0, 0, 0]);
+        let mut pin = port.pin(5).unwrap();
+        pin.start_counter(Edge::Falling).unwrap();
+        assert_eq!(10_000, pin.read_counter().unwrap());
+
+        let hz = pin.measure_frequency(Duration::from_millis(10)).unwrap();
+        assert!(hz > 0.0 && hz <= 500.0);
+        assert!(matches!(port.pin(3).unwrap().start_counter(Edge::Rising), Err(TesselError::UnsupportedPin)));
+
+        assert_eq!(vec![0x23, 0x25, 0x24, 5, 0x23, 0x15, 0x24, 5], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn pin_remembers_last_written_level() {
+        let (port, written) = mock_transport_port(&[reply::LOW.0]);
+        let mut pin = port.pin(3).unwrap();
+        assert_eq!(None, pin.last_written());
+        pin.high().unwrap();
+        assert_eq!(Some(true), pin.last_written());
+        // A live read doesn't touch the cache.
+        assert_eq!(false, pin.read().unwrap());
+        assert_eq!(Some(true), pin.last_written());
+        pin.write(false).unwrap();
+        assert_eq!(Some(false), pin.last_written());
+
+        // The read turns the pin back into an input, and the write after it
+        // into an output.
+        assert_eq!(vec![0x04, 3, 0x16, 3, 0x03, 3, 0x28, 3, 0x05, 3], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn pin_switches_mode_between_input_and_output() {
+        let (port, written) = mock_transport_port(&[reply::HIGH.0, reply::LOW.0]);
+        let mut pin = port.pin(3).unwrap();
+        pin.set_mode(PinMode::Input).unwrap();
+        pin.write(true).unwrap();
+        assert_eq!(true, pin.read().unwrap());
+        // Reading again doesn't switch it a second time.
+        assert_eq!(false, pin.read().unwrap());
+
+        assert_eq!(vec![0x16, 3, 0x28, 3, 0x04, 3, 0x16, 3, 0x03, 3, 0x03, 3], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_raw_bytes_round_trip() {
+        let (port, mut daemon) = mock_port();
+        port.raw_write(&[0x02, 2, 0xAB, 0xCD]).unwrap();
+        daemon.write_all(&[0x84, 0xAB, 0xCD]).unwrap();
+
+        let mut buf = [0; 3];
+        port.raw_read(&mut buf).unwrap();
+        assert_eq!([0x84, 0xAB, 0xCD], buf);
+        drop(port);
+        assert_eq!(vec![0x02, 2, 0xAB, 0xCD], written_bytes(daemon));
+    }
+
+    #[test]
+    fn pin_analog_reading_keeps_raw_and_voltage() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x00, 0x08]);
+        let reading = port.pin(4).unwrap().analog_reading().unwrap();
+        assert_eq!(0x0800, reading.raw);
+        assert!((reading.voltage - 1.65).abs() < 1e-6);
+        assert_eq!(AnalogReading::from_raw(0x0800), reading);
+        assert!(matches!(port.pin(2).unwrap().analog_reading(), Err(TesselError::UnsupportedPin)));
+        assert_eq!(vec![0x18, 4], *written.lock().unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_types_are_serializable() {
+        fn assert_serde<T: ::serde::Serialize + ::serde::de::DeserializeOwned>() {}
+        assert_serde::<AnalogReading>();
+        assert_serde::<PinMode>();
+        assert_serde::<PortMode>();
+        assert_serde::<I2cAddress>();
+        assert_serde::<McuProfile>();
+        assert_serde::<PinCapabilities>();
+        assert_serde::<Endian>();
+    }
+
+    #[test]
+    fn port_reports_pin_capabilities() {
+        let (port, written) = mock_transport_port(&[]);
+        assert_eq!(PinCapabilities::I2C, port.pin_capabilities(0));
+        assert_eq!(PinCapabilities::SPI | PinCapabilities::INTERRUPT, port.pin_capabilities(2));
+        let pwm = port.pin_capabilities(5);
+        assert!(pwm.contains(PinCapabilities::ANALOG | PinCapabilities::PWM));
+        assert!(!pwm.contains(PinCapabilities::SPI));
+        assert!(!port.pin_capabilities(7).contains(PinCapabilities::PWM));
+        assert!(port.pin_capabilities(PORT_PIN_COUNT).is_empty());
+        assert!(written.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn port_exposes_socket_fd() {
+        let (port, mut daemon) = mock_port();
+        let fd = port.as_raw_fd();
+        assert!(fd >= 0);
+        assert!(fd != daemon.as_raw_fd());
+
+        // The descriptor is the socket the port writes to.
+        let mut stream = unsafe { <UnixStream as ::std::os::unix::io::FromRawFd>::from_raw_fd(fd) };
+        stream.write_all(&[0x00]).unwrap();
+        ::std::mem::forget(stream);
+        let mut byte = [0xFF];
+        daemon.read_exact(&mut byte).unwrap();
+        assert_eq!([0x00], byte);
+    }
+
+    #[test]
+    fn port_pins_fails_if_one_is_held() {
+        let (port, _) = mock_transport_port(&[]);
+        let held = port.clone().take_pin(6).unwrap();
+        assert!(matches!(port.pins(), Err(TesselError::PinBusy)));
+        // Pin 5 was taken and given back.
+        assert!(port.pin(5).is_ok());
+        drop(held);
+        let (pin5, pin6, pin7) = port.pins().unwrap();
+        assert_eq!((5, 6, 7), (pin5.index, pin6.index, pin7.index));
+    }
+
+    #[test]
+    fn port_take_pin_outlives_port() {
+        struct Driver {
+            port: Port,
+            pin: Pin<'static>,
+        }
+
+        let (port, written) = mock_transport_port(&[]);
+        let mut driver = Driver { pin: port.take_pin(3).unwrap(), port: port };
+        assert!(matches!(driver.port.pin(3), Err(TesselError::PinBusy)));
+        let other = driver.port.clone();
+        drop(driver.port);
+
+        // The pin still works, and is still held, after the port is gone.
+        driver.pin.high().unwrap();
+        assert!(matches!(other.take_pin(3), Err(TesselError::PinBusy)));
+        drop(driver.pin);
+        assert!(other.take_pin(3).is_ok());
+        assert!(matches!(other.take_pin(8), Err(TesselError::UnsupportedPin)));
+        assert_eq!(vec![0x04, 3], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_reads_and_writes_pins_together() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0b1010_0101]);
+        assert_eq!(0b0000_0100, port.read_pins(0b0000_1110).unwrap());
+        assert_eq!(0, port.read_pins(0).unwrap());
+        port.write_pins(0b0011_0000, 0b1110_0000).unwrap();
+        port.write_pins(0, 0xFF).unwrap();
+        assert_eq!(vec![0x29, 0b0000_1110, 0x2A, 0b0011_0000, 0b0010_0000], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_write_pins_respects_locks() {
+        let (port, written) = mock_transport_port(&[]);
+        let pin = port.take_pin(4).unwrap();
+        assert!(matches!(port.write_pins(0b0001_1000, 0xFF), Err(TesselError::PinBusy)));
+        drop(pin);
+
+        let (i2c, _) = port.clone().i2c().unwrap();
+        written.lock().unwrap().clear();
+        assert!(matches!(port.write_pins(0b0000_0011, 0), Err(TesselError::PortBusy)));
+        // Pins I2C doesn't use can still be driven.
+        port.write_pins(0b0000_1100, 0b0000_0100).unwrap();
+        drop(i2c);
+        assert_eq!(vec![0x2A, 0b0000_1100, 0b0000_0100, 0x0D], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_reads_analog_pins_in_one_command() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x34, 0x12, 0xFF, 0x0F]);
+        assert_eq!(vec![0x1234, 0x0FFF], port.analog_read_many(&[7, 4]).unwrap());
+        assert_eq!(Vec::<u16>::new(), port.analog_read_many(&[]).unwrap());
+        assert!(matches!(port.analog_read_many(&[4, 2]), Err(TesselError::UnsupportedPin)));
+        assert_eq!(vec![0x21, 2, 7, 4], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_firmware_version() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 1, 2, 3, reply::NACK.0, reply::HIGH.0]);
+        assert_eq!(Some((1, 2, 3)), port.firmware_version().unwrap());
+        // Older firmware doesn't know the command.
+        assert_eq!(None, port.firmware_version().unwrap());
+        assert!(matches!(port.firmware_version(), Err(TesselError::InvalidReply)));
+        assert_eq!(vec![0x1F, 0x1F, 0x1F], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn tessel_device_id_reads_first_address() {
+        let dir = env::temp_dir().join(format!("tessel-device-id-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (missing, empty, wlan) = (dir.join("missing"), dir.join("empty"), dir.join("wlan"));
+        fs::write(&empty, "\n").unwrap();
+        fs::write(&wlan, "02:A3:1F:00:4C:10\n").unwrap();
+
+        assert_eq!("02:a3:1f:00:4c:10", Tessel::read_device_id(&[&missing, &empty, &wlan]).unwrap());
+        let err = Tessel::read_device_id(&[&missing, &empty]).err().unwrap();
+        assert!(matches!(err, TesselError::Io(ref err) if err.kind() == io::ErrorKind::NotFound));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn named_leds_are_none_without_leds() {
+        let mut t = Tessel {
+            led: vec![LED::new_with_file("red", "error", tempfile::tempfile().unwrap(), 1).unwrap()],
+            button: Button::new("/nonexistent"),
+            port_a: None,
+            port_b: None,
+        };
+        assert!(t.named_leds().error().is_some());
+        assert!(t.named_leds().user1().is_none());
+
+        t.led.clear();
+        assert!(t.named_leds().error().is_none());
+    }
+
+    #[test]
+    fn tessel_shutdown_turns_everything_off() {
+        let files: Vec<_> = (0..2).map(|_| tempfile::tempfile().unwrap()).collect();
+        let (port, written) = mock_transport_port(&[]);
+        let mut t = Tessel {
+            led: files.iter()
+                .map(|file| LED::new_with_file("red", "error", file.try_clone().unwrap(), 1).unwrap())
+                .collect(),
+            button: Button::new("/nonexistent"),
+            port_a: Some(port.clone()),
+            port_b: None,
+        };
+        t.set_leds(&[(0, true), (1, true)]).unwrap();
+        let _uart = port.uart(9600).unwrap();
+
+        t.shutdown().unwrap();
+        assert!(t.led.iter().all(|led| !led.read()));
+        assert_eq!(Some(&0x0F), written.lock().unwrap().last());
+    }
+
+    #[test]
+    fn tessel_shutdown_disables_ports_from_ports() {
+        let (a, written) = mock_transport_port(&[]);
+        let (b, _) = mock_transport_port(&[]);
+        // As if ports() had handed out a and b.
+        *ISSUED_PORTS.lock().unwrap() = Some((a.clone(), b));
+        let mut t = Tessel { led: vec![], button: Button::new("/nonexistent"), port_a: None, port_b: None };
+        let _i2c = a.i2c().unwrap();
+
+        t.shutdown().unwrap();
+        assert_eq!(Some(&0x0D), written.lock().unwrap().last());
+        *ISSUED_PORTS.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn tessel_sets_leds_together() {
+        let files: Vec<_> = (0..2).map(|_| tempfile::tempfile().unwrap()).collect();
+        let mut t = Tessel {
+            led: files.iter()
+                .map(|file| LED::new_with_file("red", "error", file.try_clone().unwrap(), 1).unwrap())
+                .collect(),
+            button: Button::new("/nonexistent"),
+            port_a: None,
+            port_b: None,
+        };
+
+        t.set_leds(&[(0, true), (1, true)]).unwrap();
+        assert!(t.led.iter().all(|led| led.read()));
+        assert!(t.set_leds(&[(0, false), (2, false)]).is_err());
+        assert!(t.led[0].read());
+        t.all_leds_off().unwrap();
+        assert!(t.led.iter().all(|led| !led.read()));
+    }
+
+    #[test]
+    fn debug_shows_state() {
+        let tmpfile = tempfile::tempfile().unwrap();
+        let mut led = LED::new_with_file("red", "error", tmpfile, 1).unwrap();
+        led.on().unwrap();
+        assert_eq!("LED { color: \"red\", kind: \"error\", brightness: 1, max_brightness: 1 }", format!("{:?}", led));
+
+        let (port, _written) = mock_transport_port(&[]);
+        assert_eq!("Pin { index: 3, held: true, value: None }", format!("{:?}", port.pin(3).unwrap()));
+        let (i2c, _) = port.i2c().unwrap();
+        assert_eq!("I2cPort { frequency: 100000, timeout: None, held: false }", format!("{:?}", i2c));
+    }
+
+    #[test]
+    fn button_reads_from_file() {
+        let mut tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let button = Button::new(tmpfile.path());
+        tmpfile.write_all(b"1\n").unwrap();
+        assert_eq!(true, button.read().unwrap());
+        tmpfile.seek(SeekFrom::Start(0)).unwrap();
+        tmpfile.write_all(b"0\n").unwrap();
+        assert_eq!(false, button.read().unwrap());
+        assert!(!button.is_pressed().unwrap());
+
+        // A button that can't be read isn't reported as released.
+        let button = Button::new(tmpfile.path().with_extension("missing"));
+        assert!(button.is_pressed().is_err());
+    }
+
+    #[test]
+    fn button_press_is_debounced() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let path = tmpfile.path().to_path_buf();
+        let button = Button::new(&path);
+        std::fs::write(&path, "0\n").unwrap();
+
+        let presser = thread::spawn(move || {
+            // Bounce, then hold the button down.
+            for &(delay, level) in &[(50, "1\n"), (15, "0\n"), (15, "1\n")] {
+                thread::sleep(Duration::from_millis(delay));
+                // Replace the file whole, so the button never reads it half-written.
+                let next = path.with_extension("next");
+                std::fs::write(&next, level).unwrap();
+                std::fs::rename(&next, &path).unwrap();
+            }
+        });
+        let start = Instant::now();
+        button.wait_for_press_debounced(Duration::from_millis(30)).unwrap();
+        // The bounce restarts the debounce window.
+        assert!(start.elapsed() >= Duration::from_millis(110));
+        assert!(button.is_pressed().unwrap());
+        presser.join().unwrap();
+    }
+
+    #[test]
+    fn i2c_send_chunks_large_buffers() {
+        let (port, mut daemon) = mock_port();
+        daemon.write_all(&[reply::ACK.0]).unwrap();
+        {
+            let (mut i2c, _) = port.i2c().unwrap();
+            i2c.send(0x10, &[0xAA; 512]).unwrap();
+        }
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x20]);
+        expected.extend_from_slice(&[0x10, 255]);
+        expected.extend_from_slice(&[0xAA; 255]);
+        expected.extend_from_slice(&[0x10, 255]);
+        expected.extend_from_slice(&[0xAA; 255]);
+        expected.extend_from_slice(&[0x10, 2, 0xAA, 0xAA]);
+        expected.extend_from_slice(&[0x14, 0x0D]);
+        assert_eq!(expected, written_bytes(daemon));
+    }
+
+    #[test]
+    fn i2c_read_chunks_large_buffers() {
+        let mut replies = vec![reply::DATA.0];
+        replies.extend_from_slice(&[0xAA; 255]);
+        replies.push(reply::DATA.0);
+        replies.extend_from_slice(&[0xBB; 255]);
+        replies.extend_from_slice(&[reply::DATA.0, 0xCC, 0xCC]);
+        let (port, written) = mock_transport_port(&replies);
+
+        let mut buf = [0; 512];
+        {
+            let (mut i2c, _) = port.i2c().unwrap();
+            i2c.read(0x10, &mut buf).unwrap();
+        }
+        assert!(buf[..255].iter().all(|&x| x == 0xAA));
+        assert!(buf[255..510].iter().all(|&x| x == 0xBB));
+        assert_eq!([0xCC, 0xCC], buf[510..]);
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x21,
+                                     0x11, 255, 0x11, 255, 0x11, 2, 0x14, 0x0D]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_partial_read_counts_received_bytes() {
+        let mut replies = vec![reply::DATA.0];
+        replies.extend_from_slice(&[0xAA; 255]);
+        replies.extend_from_slice(&[reply::NACK.0, reply::NACK.0, reply::NACK.0]);
+        let (port, written) = mock_transport_port(&replies);
+
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 600];
+        assert_eq!(255, i2c.read_partial(0x10, &mut buf).unwrap());
+        assert!(buf[..255].iter().all(|&x| x == 0xAA));
+        assert!(buf[255..].iter().all(|&x| x == 0));
+        // The slave ignored its address this time.
+        assert_eq!(0, i2c.transfer_partial(0x10, &[0x01], &mut buf[..2]).unwrap());
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x21, 0x11, 255, 0x11, 255, 0x11, 90, 0x14,
+                                     0x13, 0x20, 0x10, 1, 0x01, 0x13, 0x21, 0x11, 2, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn soft_i2c_reads_bits_from_sda() {
+        let h = reply::HIGH.0;
+        let l = reply::LOW.0;
+        // START: SCL released. Address 0x10 read: eight SCL releases, then
+        // SCL and a low SDA for the ACK.
+        let mut replies = vec![h];
+        replies.extend_from_slice(&[h; 8]);
+        replies.extend_from_slice(&[h, l]);
+        // 0xA5, MSB first, each bit an SCL release then an SDA sample.
+        for i in (0..8).rev() {
+            replies.push(h);
+            replies.push(if 0xA5 >> i & 1 == 1 { h } else { l });
+        }
+        // The NACK of the last byte, then STOP.
+        replies.extend_from_slice(&[h, h]);
+        let (port, written) = mock_transport_port(&replies);
+
+        let mut i2c = port.soft_i2c(5, 6, 1_000_000).unwrap();
+        let mut buf = [0];
+        i2c.read(0x10, &mut buf).unwrap();
+        assert_eq!([0xA5], buf);
+        assert_eq!(&[0x06, 0x15, 0x1A, 0x15, 0x04, 5], &written.lock().unwrap()[..6]);
+        assert!(port.pin(5).is_err());
+    }
+
+    #[test]
+    fn soft_i2c_fails_on_missing_ack() {
+        let (port, _) = mock_transport_port(&[reply::HIGH.0; 12]);
+        assert!(port.soft_i2c(2, 2, 100_000).is_err());
+
+        let mut i2c = port.soft_i2c(2, 3, 1_000_000).unwrap();
+        assert!(matches!(i2c.send(0x10, &[0x01]), Err(TesselError::Nack)));
+        let (scl, sda) = i2c.free();
+        assert_eq!((Some(true), Some(true)), (scl.last_written(), sda.last_written()));
+    }
+
+    #[test]
+    fn i2c_read_assembles_split_data_frames() {
+        let mut replies = vec![reply::DATA.0];
+        replies.extend((0..255).map(|i| i as u8));
+        // A pin change can arrive between the frames.
+        replies.push(reply::ASYNC_PIN_CHANGE_N.0 + 2);
+        replies.push(reply::DATA.0);
+        replies.extend((0..45).map(|i| 0x80 + i as u8));
+        let (port, _written) = mock_transport_port(&replies);
+        let other = port.clone();
+
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 300];
+        i2c.read(0x10, &mut buf).unwrap();
+        assert!((0..255).all(|i| buf[i] == i as u8));
+        assert!((255..300).all(|i| buf[i] == 0x80 + (i - 255) as u8));
+        assert_eq!(Some((2, false)), other.socket().take_pin_change());
+    }
+
+    #[test]
+    fn i2c_ten_bit_addresses() {
+        let (port, daemon) = mock_port();
+        {
+            let (mut i2c, _) = port.i2c().unwrap();
+            let mut buf = [0; 0];
+            // 0x80 does not fit in 7 bits.
+            assert!(i2c.read(0x80, &mut buf).is_err());
+            i2c.read(I2cAddress::TenBit(0x2A5), &mut buf).unwrap();
+        }
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0xF4,
+                                     0x10, 1, 0xA5, 0x13, 0xF5, 0x14, 0x0D]);
+        assert_eq!(expected, written_bytes(daemon));
+    }
+
+    #[test]
+    fn i2c_reserved_addresses_need_explicit_opt_in() {
+        let (port, written) = mock_transport_port(&[reply::ACK.0]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        assert!(matches!(i2c.send(0x00, &[0x06]), Err(TesselError::InvalidArgument(_))));
+        assert!(matches!(i2c.send(0x7B, &[]), Err(TesselError::InvalidArgument(_))));
+        assert!(matches!(i2c.send(I2cAddress::Reserved(0x40), &[]), Err(TesselError::InvalidArgument(_))));
+        i2c.software_reset().unwrap();
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x00, 0x10, 1, 0x06, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_stretch_timeout() {
+        let (port, daemon) = mock_port();
+        let config = I2cConfig::default().with_frequency(Some(I2C_FAST_MODE)).with_stretch_timeout(Some(10));
+        {
+            let (mut i2c, _) = port.i2c_with_config(config).unwrap();
+            // The daemon never replies.
+            let mut buf = [0; 1];
+            let err = i2c.read(0x10, &mut buf).err().unwrap();
+            assert!(matches!(err, TesselError::TimedOut));
+        }
+
+        let mut expected = i2c_enable_prefix(400_000);
+        expected.extend_from_slice(&[0x1D, 0, 10,
+                                     0x13, 0x21, 0x11, 1, 0x14, 0x0D]);
+        assert_eq!(expected, written_bytes(daemon));
+    }
+
+    #[test]
+    fn i2c_read_retries_timed_out_replies() {
+        let (port, daemon) = mock_port();
+        let config = I2cConfig::default().with_reply_timeout(Some(Duration::from_millis(10))).with_retries(2);
+        {
+            let (mut i2c, _) = port.i2c_with_config(config).unwrap();
+            // The daemon never replies, so every attempt times out.
+            let mut buf = [0; 1];
+            assert!(matches!(i2c.read(0x10, &mut buf), Err(TesselError::TimedOut)));
+        }
+
+        let mut expected = i2c_enable_prefix(I2C_STANDARD_MODE);
+        for _ in 0..3 {
+            expected.extend_from_slice(&[0x13, 0x21, 0x11, 1, 0x14]);
+        }
+        expected.push(0x0D);
+        assert_eq!(expected, written_bytes(daemon));
+    }
+
+    #[test]
+    fn i2c_failed_transactions_still_send_stop() {
+        let enable = i2c_enable_prefix(I2C_STANDARD_MODE);
+
+        // The batched write of a send() fails as a whole, so no START got
+        // out and no STOP follows.
+        let written = Arc::new(Mutex::new(vec![]));
+        let transport = FailingTransport { written: written.clone(), fail_on: 0x13 };
+        let port = Port::new_with_socket(PortSocket::from_transport(Box::new(transport)));
+        let (mut i2c, _) = port.i2c().unwrap();
+        assert!(matches!(i2c.send(0x50, &[0x01]), Err(TesselError::Io(_))));
+        assert_eq!(enable, *written.lock().unwrap());
+
+        // The RX of a transaction fails after its STARTs went out.
+        let written = Arc::new(Mutex::new(vec![]));
+        let transport = FailingTransport { written: written.clone(), fail_on: 0x11 };
+        let port = Port::new_with_socket(PortSocket::from_transport(Box::new(transport)));
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 1];
+        let result = i2c.transaction(0x50, &mut [I2cOp::Write(&[0x01]), I2cOp::Read(&mut buf)]);
+        assert!(matches!(result, Err(TesselError::Io(_))));
+        let mut expected = enable.clone();
+        expected.extend_from_slice(&[0x13, 0xA0, 0x10, 1, 0x01, 0x13, 0xA1, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
 
     #[test]
-    fn led_writes_to_file() {
-        let mut tmpfile = tempfile::tempfile().unwrap();
-        // The tmpfile handle can be reused as long as LED gets its own
-        // clone of the handle, and we are diligent about seeking.
-        // This avoids needing to figure out where the tmpfile is in order
-        // to open more handles.
-        let mut led = LED::new_with_file(tmpfile.try_clone().unwrap());
-        let mut buf = String::new();
-        tmpfile.seek(SeekFrom::Start(0)).unwrap();
-        tmpfile.read_to_string(&mut buf).unwrap();
-        assert_eq!("0", buf);
-        led.on().unwrap();
-        tmpfile.seek(SeekFrom::Start(0)).unwrap();
-        tmpfile.read_to_string(&mut buf).unwrap();
-        // b'1' is written as 001 into the file.
-        assert_eq!("001", buf);
+    fn i2c_send_over_mock_transport() {
+        let (port, written) = mock_transport_port(&[reply::ACK.0, reply::NACK.0]);
+        let (mut i2c, _) = port.i2c_with_config(I2cConfig { frequency: 400_000, ..I2cConfig::default() }).unwrap();
+        i2c.send(0x1D, &[0x2A, 0x01]).unwrap();
+        // Nothing answers at the second address.
+        assert!(matches!(i2c.send(0x1E, &[]), Err(TesselError::Nack)));
+
+        let mut expected = i2c_enable_prefix(400_000);
+        expected.extend_from_slice(&[0x13, 0x3A, 0x10, 2, 0x2A, 0x01, 0x14,
+                                     0x13, 0x3C, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_send_gather_packs_buffers_into_one_write() {
+        let (port, written) = mock_transport_port(&[reply::ACK.0, reply::ACK.0]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        i2c.send_gather(0x40, &[&[0x06], &[0x01, 0x02], &[]]).unwrap();
+        // Slices longer than one TX command carry over to the next.
+        let data = [0xAA; 300];
+        i2c.send_gather(0x40, &[&[0x06], &data]).unwrap();
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x80, 0x10, 3, 0x06, 0x01, 0x02, 0x14,
+                                     0x13, 0x80, 0x10, 255, 0x06]);
+        expected.extend_from_slice(&[0xAA; 254]);
+        expected.extend_from_slice(&[0x10, 46]);
+        expected.extend_from_slice(&[0xAA; 46]);
+        expected.push(0x14);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_scan_finds_acking_addresses() {
+        let replies: Vec<u8> = (0x08..0x78)
+            .map(|address| if address == 0x1D || address == 0x40 { reply::ACK.0 } else { reply::NACK.0 })
+            .collect();
+        let (port, written) = mock_transport_port(&replies);
+        let (mut i2c, _) = port.i2c().unwrap();
+        assert_eq!(vec![0x1D, 0x40], i2c.scan().unwrap());
+
+        let written = written.lock().unwrap();
+        assert_eq!([0x13, 0x10, 0x14], written[6..9]);
+        assert_eq!([0x13, 0xEE, 0x14], written[written.len() - 3..]);
+    }
+
+    #[test]
+    fn i2c_register_helpers() {
+        let (port, written) = mock_transport_port(&[reply::ACK.0, reply::DATA.0, 0x2A]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        i2c.write_register(0x1D, 0x2A, &[0x01]).unwrap();
+        let mut buf = [0; 1];
+        i2c.read_register(0x1D, 0x0D, &mut buf).unwrap();
+        assert_eq!([0x2A], buf);
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x3A, 0x10, 2, 0x2A, 0x01, 0x14,
+                                     0x13, 0x3A, 0x10, 1, 0x0D, 0x13, 0x3B, 0x11, 1, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_transfer_start_bytes_carry_direction() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x55]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 1];
+        i2c.transfer(0x48, &[0x01], &mut buf).unwrap();
+
+        // The write phase STARTs with 0x90, and the read phase with 0x91.
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x90, 0x10, 1, 0x01, 0x13, 0x91, 0x11, 1, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_read_over_mock_transport() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x12, 0x34]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 2];
+        i2c.read(0x40, &mut buf).unwrap();
+        assert_eq!([0x12, 0x34], buf);
+        // There is no reply left for another read.
+        assert!(i2c.read(0x40, &mut buf).is_err());
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x81, 0x11, 2, 0x14,
+                                     0x13, 0x81, 0x11, 2, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_transaction_restarts_on_direction_change() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x42]);
+
+        let mut buf = [0; 1];
+        {
+            let (mut i2c, _) = port.i2c().unwrap();
+            i2c.transaction(0x10, &mut [I2cOp::Write(&[0x01]),
+                                        I2cOp::Write(&[0x02]),
+                                        I2cOp::Read(&mut buf)]).unwrap();
+        }
+        assert_eq!([0x42], buf);
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x20,
+                                     0x10, 1, 0x01, 0x10, 1, 0x02, 0x13, 0x21, 0x11, 1, 0x14, 0x0D]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_actual_frequency_is_close_to_requested() {
+        for &frequency in &[I2C_STANDARD_MODE, I2C_FAST_MODE, 1_000_000] {
+            let config = I2cConfig::default().with_frequency(Some(frequency));
+            let actual = config.check_frequency(0.05).unwrap();
+            assert_eq!(actual, config.actual_frequency());
+            assert!(actual != frequency);
+        }
+        assert_eq!(100_267, I2cConfig::default().actual_frequency());
+
+        // Too slow for the baud register.
+        let config = I2cConfig::default().with_frequency(Some(10_000));
+        assert!(config.check_frequency(0.05).is_err());
+    }
+
+    #[test]
+    fn i2c_enable_errors_are_returned() {
+        let (port, _) = failing_transport_port(0x0C);
+        assert!(matches!(port.clone().i2c(), Err(TesselError::Io(_))));
+        assert_eq!(PortMode::Gpio, port.mode());
+
+        let (port, _) = failing_transport_port(0x1D);
+        let config = I2cConfig::default().with_stretch_timeout(Some(10));
+        assert!(matches!(port.clone().i2c_with_config(config), Err(TesselError::Io(_))));
+        assert_eq!(PortMode::Gpio, port.mode());
+    }
+
+    #[test]
+    fn uart_enable_errors_are_returned() {
+        let (port, _) = failing_transport_port(0x0E);
+        assert!(matches!(port.clone().uart(9600), Err(TesselError::Io(_))));
+        assert_eq!(PortMode::Gpio, port.mode());
+    }
+
+    #[test]
+    fn with_i2c_releases_port_afterwards() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x2A]);
+        let id = port.with_i2c(I2C_FAST_MODE, |i2c| {
+            assert_eq!(PortMode::I2c, port.mode());
+            assert!(matches!(port.pin(0), Err(TesselError::PinBusy)));
+            i2c.read_byte(0x1D)
+        }).unwrap();
+        assert_eq!(0x2A, id);
+        assert_eq!(PortMode::Gpio, port.mode());
+        assert_eq!(Some(&0x0D), written.lock().unwrap().last());
+        assert!(port.pin(0).is_ok());
+
+        let _sda = port.pin(1).unwrap();
+        assert!(matches!(port.with_i2c(I2C_STANDARD_MODE, |_| Ok(())), Err(TesselError::PinBusy)));
+    }
+
+    #[test]
+    fn try_i2c_rejects_unreachable_frequencies() {
+        for &frequency in &[0, 1_000, 10_000_000] {
+            let (port, written) = mock_transport_port(&[]);
+            let config = I2cConfig::default().with_frequency(Some(frequency));
+            assert!(matches!(port.clone().try_i2c(config, 0.05), Err(TesselError::InvalidArgument(_))));
+            assert_eq!(PortMode::Gpio, port.mode());
+            assert!(written.lock().unwrap().is_empty());
+        }
+
+        let (port, _) = mock_transport_port(&[]);
+        let (i2c, _) = port.try_i2c(I2cConfig::default(), 0.05).unwrap();
+        assert_eq!(100_267, i2c.actual_frequency());
+    }
+
+    #[test]
+    fn i2c_single_byte_helpers() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x5A, reply::ACK.0]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        assert_eq!(0x5A, i2c.read_byte(0x10).unwrap());
+        i2c.write_byte(0x10, 0xF3).unwrap();
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x21, 0x11, 1, 0x14, 0x13, 0x20, 0x10, 1, 0xF3, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_reads_multi_byte_registers() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x12, 0x34, reply::DATA.0, 0x12, 0x34,
+                                                    reply::DATA.0, 0x01, 0x02, 0x03, 0x04]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        assert_eq!(0x1234, i2c.read_u16_register(0x40, 0xE3, Endian::Big).unwrap());
+        assert_eq!(0x3412, i2c.read_u16_register(0x40, 0xE3, Endian::Little).unwrap());
+        assert_eq!(0x04030201, i2c.read_u32_register(0x40, 0x10, Endian::Little).unwrap());
+
+        let mut expected = i2c_enable_prefix(100_000);
+        for &(reg, len) in &[(0xE3, 2), (0xE3, 2), (0x10, 4)] {
+            expected.extend_from_slice(&[0x13, 0x80, 0x10, 1, reg, 0x13, 0x81, 0x11, len, 0x14]);
+        }
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_16_bit_registers_are_sent_msb_first() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x11, 0x22, reply::ACK.0]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 2];
+        i2c.read_from_register16(0x50, 0x1234, &mut buf).unwrap();
+        assert_eq!([0x11, 0x22], buf);
+        i2c.write_register16(0x50, 0x0102, &[0xAB]).unwrap();
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0xA0, 0x10, 2, 0x12, 0x34, 0x13, 0xA1, 0x11, 2, 0x14,
+                                     0x13, 0xA0, 0x10, 3, 0x01, 0x02, 0xAB, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_no_stop_holds_the_bus() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x42, reply::ACK.0, reply::NACK.0]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0];
+
+        // Register read as two calls: no ACK, since the transaction has a read.
+        i2c.send_no_stop(0x10, &[0x01]).unwrap();
+        i2c.read_no_stop(0x10, &mut buf).unwrap();
+        i2c.send(0x10, &[0x02]).unwrap();
+        assert_eq!([0x42], buf);
+
+        i2c.send_no_stop(0x10, &[0x03]).unwrap();
+        i2c.send_stop().unwrap();
+        i2c.send_no_stop(0x10, &[0x04]).unwrap();
+        assert!(matches!(i2c.send_stop(), Err(TesselError::Nack)));
+        // The bus is no longer held.
+        i2c.send_stop().unwrap();
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x20, 0x10, 1, 0x01, 0x13, 0x21, 0x11, 1, 0x13, 0x20, 0x10, 1, 0x02, 0x14,
+                                     0x13, 0x20, 0x10, 1, 0x03, 0x14, 0x13, 0x20, 0x10, 1, 0x04, 0x14]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_recover_bus_pulses_until_sda_released() {
+        let (l, h) = (reply::LOW.0, reply::HIGH.0);
+        let (port, written) = mock_transport_port(&[l, l, h, h, l, l, l, l, l, l, l, l, l, l]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        written.lock().unwrap().clear();
+
+        i2c.recover_bus().unwrap();
+        let mut expected = vec![0x0D, 0x06, 0x10, 0x04, 0, 0x06, 0x11, 0x04, 1,
+                                0x17, 1, 0x05, 0, 0x04, 0, 0x17, 1, 0x05, 0, 0x04, 0, 0x17, 1,
+                                0x05, 0, 0x05, 1, 0x04, 0, 0x04, 1, 0x17, 1];
+        expected.extend(i2c_enable_prefix(100_000));
+        assert_eq!(expected, *written.lock().unwrap());
+
+        // A slave that never lets go.
+        assert!(i2c.recover_bus().is_err());
+    }
+
+    #[test]
+    fn i2c_recover_bus_reenables_i2c_when_it_fails() {
+        // No reply to the first SDA read.
+        let (port, written) = mock_transport_port(&[]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        written.lock().unwrap().clear();
+
+        assert!(i2c.recover_bus().is_err());
+        let mut expected = vec![0x0D, 0x06, 0x10, 0x04, 0, 0x06, 0x11, 0x04, 1, 0x17, 1];
+        expected.extend(i2c_enable_prefix(100_000));
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_disables_on_drop() {
+        let (port, daemon) = mock_port();
+        {
+            let _ = port.i2c().unwrap();
+        }
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x0D]);
+        assert_eq!(expected, written_bytes(daemon));
+    }
+
+    #[test]
+    fn i2c_read_discards_stale_replies() {
+        let (port, mut daemon) = mock_port();
+        // Left over from a read that was abandoned partway through.
+        daemon.write_all(&[reply::DATA.0, 0xFF, 0xFF]).unwrap();
+
+        let replier = thread::spawn(move || {
+            // Answer only once the whole read has been requested.
+            let mut commands = [0; 11];
+            daemon.read_exact(&mut commands).unwrap();
+            daemon.write_all(&[reply::DATA.0, 0x42]).unwrap();
+            daemon
+        });
+
+        let (mut i2c, _) = port.i2c().unwrap();
+        let mut buf = [0; 1];
+        i2c.read(0x10, &mut buf).unwrap();
+        assert_eq!([0x42], buf);
+        replier.join().unwrap();
+    }
+
+    #[test]
+    fn i2c_free_returns_bus_pins() {
+        let (port, written) = mock_transport_port(&[]);
+        let (i2c, _) = port.clone().i2c().unwrap();
+        // The bus pins are held while I2C is enabled...
+        assert!(matches!(port.pin(0), Err(TesselError::PinBusy)));
+        assert!(matches!(port.pin(1), Err(TesselError::PinBusy)));
+        let (mut scl, mut sda) = i2c.free();
+        scl.high().unwrap();
+        sda.low().unwrap();
+        // ...and by the returned pins after it.
+        assert!(matches!(port.pin(0), Err(TesselError::PinBusy)));
+        drop(scl);
+        assert!(port.pin(0).is_ok());
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x0D, 0x04, 0, 0x05, 1]);
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_needs_bus_pins() {
+        let (port, written) = mock_transport_port(&[]);
+        let sda = port.take_pin(1).unwrap();
+        assert!(matches!(port.clone().i2c(), Err(TesselError::PinBusy)));
+        assert_eq!(PortMode::Gpio, port.mode());
+        // SCL was given back.
+        assert!(port.pin(0).is_ok());
+        drop(sda);
+        assert!(port.clone().i2c().is_ok());
+        assert_eq!(Some(&0x0D), written.lock().unwrap().last());
+    }
+
+    #[test]
+    fn spi_free_returns_bus_pins() {
+        let (port, written) = mock_transport_port(&[]);
+        let (spi, _) = port.clone().spi(2_000_000, SpiMode::Mode0).unwrap();
+        // The bus pins are held while SPI is enabled...
+        for &index in SPI_PINS.iter() {
+            assert!(matches!(port.pin(index), Err(TesselError::PinBusy)));
+        }
+        written.lock().unwrap().clear();
+        let (mut sck, _miso, _mosi) = spi.free();
+        sck.high().unwrap();
+        // ...and by the returned pins after it.
+        assert!(matches!(port.pin(2), Err(TesselError::PinBusy)));
+        drop(sck);
+        assert!(port.pin(2).is_ok());
+
+        assert_eq!(vec![0x0B, 0x04, 2], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn spi_needs_bus_pins() {
+        let (port, written) = mock_transport_port(&[]);
+        let mosi = port.take_pin(4).unwrap();
+        assert!(matches!(port.clone().spi(2_000_000, SpiMode::Mode0), Err(TesselError::PinBusy)));
+        assert_eq!(PortMode::Gpio, port.mode());
+        assert!(written.lock().unwrap().is_empty());
+        // SCK and MISO were given back.
+        assert!(port.pin(2).is_ok());
+        assert!(port.pin(3).is_ok());
+        drop(mosi);
+        assert!(port.clone().spi(2_000_000, SpiMode::Mode0).is_ok());
+        assert_eq!(Some(&0x0B), written.lock().unwrap().last());
+    }
+
+    #[test]
+    fn uart_free_returns_bus_pins() {
+        let (port, written) = mock_transport_port(&[]);
+        let (uart, _) = port.clone().uart(9600).unwrap();
+        // TX and RX are held while the UART is enabled...
+        assert!(matches!(port.pin(5), Err(TesselError::PinBusy)));
+        assert!(matches!(port.pwm_pin(PwmPinIndex::Pin5), Err(TesselError::PinBusy)));
+        assert!(matches!(port.pin(6), Err(TesselError::PinBusy)));
+        written.lock().unwrap().clear();
+        let (mut tx, rx) = uart.free();
+        tx.low().unwrap();
+        // ...and by the returned pins after it.
+        assert!(matches!(port.pin(6), Err(TesselError::PinBusy)));
+        drop(rx);
+        assert!(port.pin(6).is_ok());
+
+        assert_eq!(vec![0x0F, 0x05, 5], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn uart_needs_bus_pins() {
+        let (port, written) = mock_transport_port(&[]);
+        let rx = port.take_pin(6).unwrap();
+        assert!(matches!(port.clone().uart(9600), Err(TesselError::PinBusy)));
+        assert_eq!(PortMode::Gpio, port.mode());
+        assert!(written.lock().unwrap().is_empty());
+        // TX was given back.
+        assert!(port.pin(5).is_ok());
+        drop(rx);
+        assert!(port.clone().uart(9600).is_ok());
+        assert_eq!(Some(&0x0F), written.lock().unwrap().last());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_i2c_matches_replies_to_requests() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        let _context = runtime.enter();
+        let (client, mut daemon) = std::os::unix::net::UnixStream::pair().unwrap();
+        let port = AsyncPort::from_std(client).unwrap();
+        let i2c = port.i2c(100_000).unwrap();
+        assert!(matches!(port.i2c(100_000), Err(TesselError::PortBusy)));
+
+        // Both requests go out before either reply is awaited.
+        let sent = i2c.send(0x40, &[0x01, 0x02]);
+        let read = i2c.read(0x40, 2);
+        daemon.write_all(&[reply::ACK.0, 0xC5, reply::DATA.0, 0x12, 0x34]).unwrap();
+        assert_eq!(vec![0x12, 0x34], runtime.block_on(read).unwrap());
+        runtime.block_on(sent).unwrap();
+
+        let nacked = i2c.send(0x41, &[]);
+        daemon.write_all(&[reply::NACK.0]).unwrap();
+        assert!(matches!(runtime.block_on(nacked), Err(TesselError::Nack)));
+        assert!(matches!(runtime.block_on(i2c.send(0x7F, &[])), Err(TesselError::InvalidArgument(_))));
+
+        let mut expected = i2c_enable_prefix(100_000);
+        expected.extend_from_slice(&[0x13, 0x80, 0x10, 2, 0x01, 0x02, 0x14,
+                                     0x13, 0x81, 0x11, 2, 0x14,
+                                     0x13, 0x82, 0x14]);
+        daemon.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut written = vec![0; expected.len()];
+        daemon.read_exact(&mut written).unwrap();
+        assert_eq!(expected, written);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_port_fails_requests_when_the_socket_closes() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build().unwrap();
+        let _context = runtime.enter();
+        let (client, daemon) = std::os::unix::net::UnixStream::pair().unwrap();
+        let port = AsyncPort::from_std(client).unwrap();
+        let i2c = port.i2c(100_000).unwrap();
+
+        let sent = i2c.send(0x40, &[0x01]);
+        drop(daemon);
+        assert!(matches!(runtime.block_on(sent), Err(TesselError::Io(_))));
+        // So does everything after.
+        assert!(matches!(runtime.block_on(i2c.read(0x40, 1)), Err(TesselError::Io(_))));
+    }
+
+    #[test]
+    fn i2c_default_enables_standard_mode() {
+        let (port, written) = mock_transport_port(&[]);
+        let (i2c, _) = port.i2c_default().unwrap();
+        assert_eq!(I2C_STANDARD_MODE, i2c.frequency);
+        assert_eq!(i2c_enable_prefix(I2C_STANDARD_MODE), *written.lock().unwrap());
+    }
+
+    #[test]
+    fn i2c_enable_makes_pins_open_drain() {
+        let (port, written) = mock_transport_port(&[]);
+        let (mut i2c, _) = port.i2c().unwrap();
+        i2c.set_frequency(I2C_FAST_MODE).unwrap();
+
+        // Both SCL and SDA go open-drain before every ENABLE_I2C.
+        let expected = vec![0x06, 0x10, 0x06, 0x11, 0x0C, I2cPort::compute_baud(&McuProfile::tessel2(), 100_000),
+                            0x06, 0x10, 0x06, 0x11, 0x0C, I2cPort::compute_baud(&McuProfile::tessel2(), 400_000)];
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_group_iterates_both_ports() {
+        let ports = PortGroup { a: mock_port().0, b: mock_port().0 };
+        assert_eq!(2, ports.iter().count());
+        assert!(ports.get('A').is_some());
+        assert!(ports.get('b').is_some());
+        assert!(ports.get('c').is_none());
+
+        // Pins held through one port don't affect the other.
+        {
+            let _pin = ports.get('a').unwrap().pin(0).unwrap();
+            assert!(ports.b.pin(0).is_ok());
+        }
+        assert_eq!(2, ports.into_iter().count());
+    }
+
+    #[test]
+    fn every_pin_is_available_after_construction() {
+        let (port, _daemon) = mock_port();
+        assert!(port.pin(0).is_ok());
+
+        let pins = port.all_pins().unwrap();
+        assert_eq!(PORT_PIN_COUNT, pins.len());
+        assert!(matches!(port.pin(0), Err(TesselError::PinBusy)));
+        drop(pins);
+        assert!(matches!(port.all_pins(), Ok(_)));
+    }
+
+    #[test]
+    fn port_clones_share_pins() {
+        let (port, _daemon) = mock_port();
+        let other = port.clone();
+        let pin = port.pin(4).unwrap();
+        assert!(matches!(other.pin(4), Err(TesselError::PinBusy)));
+        drop(pin);
+        assert!(other.pin(4).is_ok());
+    }
+
+    #[test]
+    fn ports_work_across_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Port>();
+        assert_send::<Pin>();
+        assert_send::<I2cPort>();
+        assert_send::<SpiPort>();
+        assert_send::<UartPort>();
+
+        let (port, daemon) = mock_port();
+        let other = port.clone();
+        thread::spawn(move || other.pin(2).unwrap().high().unwrap())
+            .join()
+            .unwrap();
+        drop(port);
+        assert_eq!(vec![0x04, 2], written_bytes(daemon));
+    }
+
+    #[test]
+    fn pins_are_held_until_dropped() {
+        let (port, _daemon) = mock_port();
+        let pin = port.pin(5).unwrap();
+        assert!(matches!(port.pin(5), Err(TesselError::PinBusy)));
+        assert!(port.pin(6).is_ok());
+        assert!(matches!(port.pin(8), Err(TesselError::UnsupportedPin)));
+        drop(pin);
+        assert!(port.pin_blocking(5).is_ok());
+
+        let pin = port.pin(6).unwrap();
+        pin.release();
+        assert!(port.pin(6).is_ok());
+    }
+
+    #[test]
+    fn spi_clock_fits_registers() {
+        // 24MHz is the fastest clock the SERCOM can produce.
+        assert_eq!((0, 1), SpiPort::compute_clock(&McuProfile::tessel2(), 24_000_000));
+        assert_eq!((11, 1), SpiPort::compute_clock(&McuProfile::tessel2(), 2_000_000));
+        // Slow clocks need the divisor to keep the baud register in range.
+        assert_eq!((239, 2), SpiPort::compute_clock(&McuProfile::tessel2(), 50_000));
+
+        let (port, _written) = mock_transport_port(&[]);
+        let (spi, _) = port.spi(3_000_000, SpiMode::Mode0).unwrap();
+        assert_eq!(3_000_000, spi.actual_clock_speed());
+    }
+
+    #[test]
+    fn connect_with_retry_waits_for_socket() {
+        let path = env::temp_dir().join(format!("tessel-retry-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let result = Port::connect_with_retry(&path, 3, Duration::from_millis(1));
+        assert!(matches!(result, Err(TesselError::Io(ref err)) if err.kind() == io::ErrorKind::NotFound));
+
+        let daemon_path = path.clone();
+        let daemon = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            UnixListener::bind(&daemon_path).unwrap()
+        });
+        let port = Port::connect_with_retry(&path, 10, Duration::from_millis(5)).unwrap();
+        assert_eq!(Some(path.clone()), port.socket_path());
+        drop(daemon.join().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stream_port_has_no_socket_path() {
+        let (port, _daemon) = mock_port();
+        assert_eq!(None, port.socket_path());
+    }
+
+    #[test]
+    fn switch_to_disables_and_enables() {
+        let (port, written) = mock_transport_port(&[reply::PONG.0]);
+        let (i2c, _) = port.clone().i2c().unwrap();
+        let spi = match port.switch_to(PortConfig::Spi(SpiConfig::default())).unwrap() {
+            Peripheral::Spi(spi) => spi,
+            other => panic!("expected SPI, got {:?}", other),
+        };
+        assert_eq!(PortMode::Spi, port.mode());
+        assert!(matches!(port.clone().uart(9600), Err(TesselError::PortBusy)));
+        // The stale I2C handle doesn't disable SPI, and SPI holds its pins.
+        drop(i2c);
+        assert!(matches!(port.clone().spi(2_000_000, SpiMode::Mode0), Err(TesselError::PinBusy)));
+        drop(spi);
+        assert_eq!(PortMode::Gpio, port.mode());
+
+        let written = written.lock().unwrap();
+        assert_eq!(&[0x0D, 0x26, 0x0A, 0, 11, 1, 0x0B], &written[6..]);
+    }
+
+    #[test]
+    fn switch_to_leaves_the_old_peripheral_when_a_pin_is_busy() {
+        let (port, written) = mock_transport_port(&[]);
+        let (_i2c, _) = port.clone().i2c().unwrap();
+        let _tx = port.take_pin(5).unwrap();
+        assert!(matches!(port.switch_to(PortConfig::Uart(9600)), Err(TesselError::PinBusy)));
+        assert_eq!(PortMode::I2c, port.mode());
+        assert_eq!(6, written.lock().unwrap().len());
+    }
+
+    #[test]
+    fn ping_expects_pong() {
+        let (port, written) = mock_transport_port(&[reply::PONG.0, reply::ACK.0]);
+        port.ping().unwrap();
+        assert!(matches!(port.ping(), Err(TesselError::InvalidReply)));
+        assert_eq!(vec![0x26, 0x26], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn ping_times_out_without_coprocessor() {
+        let (port, _daemon) = mock_port();
+        port.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert!(matches!(port.ping(), Err(TesselError::TimedOut)));
+    }
+
+    #[test]
+    fn reset_clears_peripheral_mode() {
+        let (port, written) = mock_transport_port(&[]);
+        port.pins.claim(PortMode::Spi).unwrap();
+        port.reset().unwrap();
+        assert_eq!(PortMode::Gpio, port.mode());
+        assert_eq!(vec![0x25], *written.lock().unwrap());
+
+        let (_uart, _) = port.clone().uart(9600).unwrap();
+        assert_eq!(PortMode::Uart, port.mode());
+    }
+
+    #[test]
+    fn mcu_profile_scales_register_values() {
+        let slow = McuProfile { clock_speed: 24_000_000, ..McuProfile::tessel2() };
+        assert_eq!(234, I2cPort::compute_baud(&McuProfile::tessel2(), 100_000));
+        assert_eq!(114, I2cPort::compute_baud(&slow, 100_000));
+
+        let (port, written) = mock_transport_port(&[]);
+        port.socket.lock().unwrap().set_profile(slow);
+        assert_eq!(slow, port.profile());
+        let (spi, _) = port.spi(2_000_000, SpiMode::Mode0).unwrap();
+        assert_eq!(2_000_000, spi.actual_clock_speed());
+        assert_eq!(vec![0x0A, 0, 5, 1], *written.lock().unwrap());
+    }
+
+    #[test]
+    fn spi_modes_send_cpol_and_cpha() {
+        let modes = [(SpiMode::Mode0, 0b00), (SpiMode::Mode1, 0b10), (SpiMode::Mode2, 0b01), (SpiMode::Mode3, 0b11)];
+        for &(mode, byte) in &modes {
+            let (port, written) = mock_transport_port(&[]);
+            let (_spi, _) = port.spi(2_000_000, mode).unwrap();
+            assert_eq!(vec![0x0A, byte, 11, 1], *written.lock().unwrap());
+        }
+    }
+
+    #[test]
+    fn spi_enable_errors_are_returned() {
+        let (port, _) = failing_transport_port(0x0A);
+        assert!(matches!(port.clone().spi(2_000_000, SpiMode::Mode0), Err(TesselError::Io(_))));
+        assert_eq!(PortMode::Gpio, port.mode());
+
+        let (port, _) = failing_transport_port(0x20);
+        let config = SpiConfig::default().with_bit_order(BitOrder::LsbFirst);
+        assert!(matches!(port.clone().spi_with_config(config), Err(TesselError::Io(_))));
+        assert_eq!(PortMode::Gpio, port.mode());
+    }
+
+    #[test]
+    fn spi_bulk_send_frames_the_buffer_once() {
+        // A 320x240 16-bit framebuffer.
+        let frame = vec![0x5A; 320 * 240 * 2];
+        let (port, written) = mock_transport_port(&[reply::ACK.0]);
+        let (mut spi, _) = port.spi(2_000_000, SpiMode::Mode0).unwrap();
+        written.lock().unwrap().clear();
+
+        spi.send_bulk(&frame).unwrap();
+        {
+            let mut written = written.lock().unwrap();
+            assert_eq!(&[0x27, 0x00, 0x02, 0x58, 0x00], &written[..5]);
+            assert_eq!(frame.len() + 5, written.len());
+            written.clear();
+        }
+
+        // Chunked TX commands spend two bytes on every 255.
+        spi.send(&frame).unwrap();
+        let chunks = (frame.len() + 254) / 255;
+        assert_eq!(frame.len() + 2 * chunks, written.lock().unwrap().len());
+    }
+
+    #[test]
+    fn spi_chip_select_wraps_transfer() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0xAB, 0xCD]);
+        let (mut spi, gpio) = port.spi(2_000_000, SpiMode::Mode0).unwrap();
+        let mut cs = gpio.pin_select(5);
+        let mut buf = [0; 2];
+        spi.transfer_with_cs(&mut cs, &[0x01, 0x02], &mut buf).unwrap();
+        assert_eq!([0xAB, 0xCD], buf);
+
+        let expected = vec![0x0A, 0, 11, 1, 0x05, 5, 0x12, 2, 0x01, 0x02, 0x04, 5];
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn spi_config_sets_bit_order_and_data_size() {
+        let (port, written) = mock_transport_port(&[]);
+        let config = SpiConfig::default()
+            .with_mode(SpiMode::Mode3)
+            .with_bit_order(BitOrder::LsbFirst)
+            .with_data_size(9);
+        let (mut spi, _) = port.spi_with_config(config).unwrap();
+        spi.set_bit_order(BitOrder::MsbFirst).unwrap();
+
+        let expected = vec![0x0A, 3, 11, 1, 0x1E, 9, 0x20, 1, 0x20, 0];
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn port_allows_one_peripheral_at_a_time() {
+        let (port, written) = mock_transport_port(&[]);
+        assert_eq!(PortMode::Gpio, port.mode());
+        let other = port.clone();
+        let (i2c, _) = port.i2c().unwrap();
+        assert_eq!(PortMode::I2c, other.mode());
+        assert!(matches!(other.clone().spi(2_000_000, SpiMode::Mode0), Err(TesselError::PortBusy)));
+        assert!(matches!(other.clone().uart(9600), Err(TesselError::PortBusy)));
+
+        // Dropping the I2C port frees the port for SPI.
+        drop(i2c);
+        assert_eq!(PortMode::Gpio, other.mode());
+        let (spi, _) = other.clone().spi(2_000_000, SpiMode::Mode0).unwrap();
+        assert_eq!(PortMode::Spi, other.mode());
+        drop(spi);
+        assert_eq!(PortMode::Gpio, other.mode());
+
+        let written = written.lock().unwrap();
+        assert_eq!([0x0D, 0x0A], written[6..8]);
+        assert_eq!(Some(&0x0B), written.last());
+    }
+
+    #[test]
+    fn spi_transfers_words() {
+        let (port, written) = mock_transport_port(&[reply::DATA.0, 0x01, 0x23, 0x00, 0x45]);
+        let (mut spi, _) = port.spi(2_000_000, SpiMode::Mode0).unwrap();
+        assert!(spi.set_data_size(16).is_err());
+        spi.set_data_size(9).unwrap();
+        assert!(spi.send_words(&[0x200]).is_err());
+
+        let mut buf = [0; 2];
+        spi.transfer_words(&[0x1FF, 0x002], &mut buf).unwrap();
+        assert_eq!([0x0123, 0x0045], buf);
+
+        let expected = vec![0x0A, 0, 11, 1, 0x1E, 9, 0x12, 4, 0x01, 0xFF, 0x00, 0x02];
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn uart_baud_matches_common_rates() {
+        assert_eq!(65326, UartPort::compute_baud(&McuProfile::tessel2(), 9600));
+        assert_eq!(64277, UartPort::compute_baud(&McuProfile::tessel2(), 57600));
+        assert_eq!(63019, UartPort::compute_baud(&McuProfile::tessel2(), 115200));
+    }
+
+    #[test]
+    fn pin_pull_sets_mode() {
+        let (port, daemon) = mock_port();
+        {
+            port.pin(5).unwrap().set_pull(Pull::Up).unwrap();
+            port.pin(2).unwrap().set_pull(Pull::None).unwrap();
+            let err = port.pin(0).unwrap().set_pull(Pull::Down).err().unwrap();
+            assert!(matches!(err, TesselError::UnsupportedPin));
+        }
+        drop(port);
+
+        assert_eq!(vec![0x1A, 0x15, 0x1A, 0x22], written_bytes(daemon));
+    }
+
+    #[test]
+    fn pin_waits_for_matching_edge() {
+        let (port, mut daemon) = mock_port();
+        // Pin 5 falls, then rises.
+        daemon.write_all(&[0xC5, 0xCD]).unwrap();
+        {
+            port.interrupt_pin(InterruptPinIndex::Pin5).unwrap().wait_for_edge(Edge::Rising).unwrap();
+            let err = port.pin(3).unwrap().wait_for_edge(Edge::Both).err().unwrap();
+            assert!(matches!(err, TesselError::UnsupportedPin));
+        }
+        drop(port);
+
+        assert_eq!(vec![0x08, 0x15, 0x08, 0x05], written_bytes(daemon));
+    }
+
+    #[test]
+    fn errors_convert_to_io_errors() {
+        assert_eq!(io::ErrorKind::WouldBlock, io::Error::from(TesselError::PinBusy).kind());
+        assert_eq!(io::ErrorKind::InvalidData, io::Error::from(TesselError::InvalidReply).kind());
+        let err = TesselError::from(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+        assert_eq!(io::ErrorKind::BrokenPipe, io::Error::from(err).kind());
+    }
+
+    #[test]
+    fn reads_time_out() {
+        let (port, daemon) = mock_port();
+        port.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        {
+            // The daemon never replies.
+            assert!(matches!(port.pin(3).unwrap().read(), Err(TesselError::TimedOut)));
+            let mut pin = port.pin(5).unwrap();
+            assert!(matches!(pin.wait_for_edge_timeout(Edge::Both, Duration::from_millis(10)),
+                             Err(TesselError::TimedOut)));
+        }
+        drop(port);
+
+        assert_eq!(vec![0x03, 3, 0x08, 0x35, 0x08, 0x05], written_bytes(daemon));
+    }
+
+    #[test]
+    fn pin_servo_write_maps_angles_to_pulse_widths() {
+        let (port, written) = mock_transport_port(&[]);
+        let mut pin = port.pin(5).unwrap();
+        // 50Hz is a period of 60000 at prescaler 4, so 1ms is 3000.
+        pin.servo_write(0.0).unwrap();
+        pin.servo_write(270.0).unwrap();
+        pin.set_servo_range(500, 2500).unwrap();
+        pin.servo_write(90.0).unwrap();
+        assert!(matches!(pin.set_servo_range(2000, 1000), Err(TesselError::InvalidArgument(_))));
+        assert!(matches!(pin.set_servo_range(1000, 30000), Err(TesselError::InvalidArgument(_))));
+        assert!(matches!(port.pin(4).unwrap().servo_write(90.0), Err(TesselError::UnsupportedPin)));
+
+        let period = [0x1C, 0x40, 0xEA, 0x60];
+        let mut expected = vec![];
+        for duty in &[[0x0B, 0xB8], [0x17, 0x70], [0x11, 0x94]] {
+            expected.extend_from_slice(&period);
+            expected.extend_from_slice(&[0x1B, 5, duty[0], duty[1]]);
+        }
+        assert_eq!(expected, *written.lock().unwrap());
+    }
+
+    #[test]
+    fn pwm_period_uses_smallest_prescaler() {
+        assert_eq!(Some((0, 48000)), Pin::compute_pwm_period(&McuProfile::tessel2(), 1000));
+        assert_eq!(Some((4, 60000)), Pin::compute_pwm_period(&McuProfile::tessel2(), 50));
+        assert_eq!(Some((7, 46875)), Pin::compute_pwm_period(&McuProfile::tessel2(), 1));
+        assert_eq!(None, Pin::compute_pwm_period(&McuProfile::tessel2(), 0));
+        assert_eq!(None, Pin::compute_pwm_period(&McuProfile::tessel2(), 100_000_000));
     }
 }
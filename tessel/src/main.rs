@@ -14,15 +14,15 @@ fn main() {
     let (_a, _b) = Tessel::ports().expect("Could not acquire Tessel ports.");
 
     // Turn on one of the LEDs
-    tessel.led[2].on().unwrap();
+    tessel.led_by_color("green").unwrap().on().unwrap();
 
     println!("I'm blinking! (Press CTRL + C to stop)");
 
     // Loop forever
     loop {
         // Toggle each LED
-        tessel.led[2].toggle().unwrap();
-        tessel.led[3].toggle().unwrap();
+        tessel.led_by_color("green").unwrap().toggle().unwrap();
+        tessel.led_by_color("blue").unwrap().toggle().unwrap();
         // Re-execute the loop after sleeping for 100ms
         sleep(Duration::from_millis(100));
     }
@@ -1,10 +1,33 @@
+use std::collections::VecDeque;
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use unix_socket::UnixStream;
+use McuProfile;
+use TesselError;
 
 use self::Command::*;
 
-mod raw_cmd {
+// The most bytes sent to the daemon in one write by default, so a long
+// stream of UART or SPI data can't overrun its buffer.
+const DEFAULT_CHUNK_SIZE: usize = 256;
+
+// Logs socket traffic at trace level when the `log` feature is enabled, and
+// compiles to nothing otherwise.
+macro_rules! trace_traffic {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "log")]
+        trace!($($arg)*);
+    }
+}
+
+/// Command bytes understood by the coprocessor, for use with
+/// `PortSocket::command()`.
+pub mod raw_cmd {
     pub const NOP: u8 = 0x00;
     pub const FLUSH: u8 = 0x01;
     pub const ECHO: u8 = 0x02;
@@ -33,6 +56,71 @@ mod raw_cmd {
     pub const GPIO_PULL: u8 = 0x1A;
     pub const PWM_DUTY_CYCLE: u8 = 0x1B;
     pub const PWM_PERIOD: u8 = 0x1C;
+    pub const I2C_TIMEOUT: u8 = 0x1D;
+    pub const SPI_DATA_SIZE: u8 = 0x1E;
+    pub const GET_VERSION: u8 = 0x1F;
+    pub const SPI_BIT_ORDER: u8 = 0x20;
+    pub const ANALOG_READ_MULTI: u8 = 0x21;
+    pub const GPIO_PULSE: u8 = 0x22;
+    pub const GPIO_COUNTER_START: u8 = 0x23;
+    pub const GPIO_COUNTER_READ: u8 = 0x24;
+    pub const RESET: u8 = 0x25;
+    pub const PING: u8 = 0x26;
+    pub const SPI_DMA: u8 = 0x27;
+    pub const GPIO_OUTPUT: u8 = 0x28;
+    pub const GPIO_READ_MASK: u8 = 0x29;
+    pub const GPIO_WRITE_MASK: u8 = 0x2A;
+
+    /// Every command byte with its name, in byte order.
+    pub const ALL: &'static [(u8, &'static str)] = &[
+        (NOP, "NOP"),
+        (FLUSH, "FLUSH"),
+        (ECHO, "ECHO"),
+        (GPIO_IN, "GPIO_IN"),
+        (GPIO_HIGH, "GPIO_HIGH"),
+        (GPIO_LOW, "GPIO_LOW"),
+        (GPIO_CFG, "GPIO_CFG"),
+        (GPIO_WAIT, "GPIO_WAIT"),
+        (GPIO_INT, "GPIO_INT"),
+        (ENABLE_SPI, "ENABLE_SPI"),
+        (DISABLE_SPI, "DISABLE_SPI"),
+        (ENABLE_I2C, "ENABLE_I2C"),
+        (DISABLE_I2C, "DISABLE_I2C"),
+        (ENABLE_UART, "ENABLE_UART"),
+        (DISABLE_UART, "DISABLE_UART"),
+        (TX, "TX"),
+        (RX, "RX"),
+        (TXRX, "TXRX"),
+        (START, "START"),
+        (STOP, "STOP"),
+        (GPIO_TOGGLE, "GPIO_TOGGLE"),
+        (GPIO_INPUT, "GPIO_INPUT"),
+        (GPIO_RAW_READ, "GPIO_RAW_READ"),
+        (ANALOG_READ, "ANALOG_READ"),
+        (ANALOG_WRITE, "ANALOG_WRITE"),
+        (GPIO_PULL, "GPIO_PULL"),
+        (PWM_DUTY_CYCLE, "PWM_DUTY_CYCLE"),
+        (PWM_PERIOD, "PWM_PERIOD"),
+        (I2C_TIMEOUT, "I2C_TIMEOUT"),
+        (SPI_DATA_SIZE, "SPI_DATA_SIZE"),
+        (GET_VERSION, "GET_VERSION"),
+        (SPI_BIT_ORDER, "SPI_BIT_ORDER"),
+        (ANALOG_READ_MULTI, "ANALOG_READ_MULTI"),
+        (GPIO_PULSE, "GPIO_PULSE"),
+        (GPIO_COUNTER_START, "GPIO_COUNTER_START"),
+        (GPIO_COUNTER_READ, "GPIO_COUNTER_READ"),
+        (RESET, "RESET"),
+        (PING, "PING"),
+        (SPI_DMA, "SPI_DMA"),
+        (GPIO_OUTPUT, "GPIO_OUTPUT"),
+        (GPIO_READ_MASK, "GPIO_READ_MASK"),
+        (GPIO_WRITE_MASK, "GPIO_WRITE_MASK"),
+    ];
+
+    /// Returns the name of a command byte, or `None` if it isn't one.
+    pub fn name(cmd: u8) -> Option<&'static str> {
+        ALL.iter().find(|&&(byte, _)| byte == cmd).map(|&(_, name)| name)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -45,21 +133,35 @@ pub enum Command<'a> {
     GpioLow(u8),
     GpioToggle(u8),
     GpioWait(u8),
-    GpioInt(u8),
-    GpioCfg(u8),
+    GpioCfg{ pin: u8, mode: u8 },
     GpioInput(u8),
+    GpioOutput(u8),
     GpioRawRead(u8),
-    GpioPull(u8),
+    GpioReadMask(u8),
+    GpioWriteMask{ mask: u8, values: u8 },
     AnalogRead(u8),
+    AnalogReadMulti(&'a [u8]),
+
+    GpioPull{ pin: u8, mode: u8 },
+    GpioInt{ pin: u8, mode: u8 },
+    GpioPulse{ pin: u8, high: bool, micros: u32 },
+    GpioCounterStart{ pin: u8, mode: u8 },
+    GpioCounterRead(u8),
 
     AnalogWrite{ pin: u8, value: u8 },
 
     EnableSpi{ mode: u8, freq: u8, div: u8 },
+    SpiDataSize(u8),
+    SpiBitOrder(u8),
     DisableSpi,
     EnableI2c{ baud: u8 },
     DisableI2c,
-    EnableUart{ baud: u8, mode: u8 },
+    I2cTimeout(u16),
+    EnableUart{ baud: u16 },
     DisableUart,
+    GetVersion,
+    Reset,
+    Ping,
 
     Start(u8),
     Stop,
@@ -70,50 +172,23 @@ pub enum Command<'a> {
     Rx(u8),
     Echo(&'a [u8]),
     Tx(&'a [u8]),
+    TxGather(&'a [&'a [u8]]),
     TxRx(&'a [u8]),
+    SpiDma(&'a [u8]),
 }
 
-/// Starting byte of reply packets. Because this is extensible, we use
-/// a list of constants instead of an enum.
-pub mod reply {
-    pub struct Reply(pub u8);
-
-    pub const ACK: Reply = Reply(0x80);
-    pub const NACK: Reply = Reply(0x81);
-    pub const HIGH: Reply = Reply(0x82);
-    pub const LOW: Reply = Reply(0x83);
-    pub const DATA: Reply = Reply(0x84);
-
-    pub const MIN_ASYNC: Reply = Reply(0xA0);
-    /// c0 to c8 is all async pin assignments.
-    pub const ASYNC_PIN_CHANGE_N: Reply = Reply(0xC0);
-    pub const ASYNC_UART_RX: Reply = Reply(0xD0);
-}
-
-/// Socket that communicates with the SAMD21.
-pub struct PortSocket {
-    _socket_path: String,
-    socket: UnixStream,
-}
-
-impl PortSocket {
-    pub fn new(path: &str) -> PortSocket {
-        // Connect to the unix domain socket for this port
-        let socket = UnixStream::connect(path).unwrap();
-
-        PortSocket {
-            _socket_path: path.to_string(),
-            socket: socket
-        }
-    }
-
-    pub fn raw_write(&mut self, buffer: &[u8]) -> io::Result<()> {
-        self.socket.write_all(buffer)
+impl<'a> Command<'a> {
+    /// Encodes the command as it goes over the socket: the command byte,
+    /// then its arguments and any data.
+    pub fn encode(self) -> Vec<u8> {
+        let mut buffer = vec![];
+        // Writing to a Vec can't fail.
+        self.encode_into(&mut buffer).unwrap();
+        buffer
     }
 
-    pub fn write_command(&mut self, cmd: Command) -> io::Result<()> {
-        let socket = &mut self.socket;
-        match cmd {
+    fn encode_into(self, socket: &mut Vec<u8>) -> io::Result<()> {
+        match self {
             Nop => socket.write_all(&[raw_cmd::NOP]),
             Flush => socket.write_all(&[raw_cmd::FLUSH]),
             Rx(len) => socket.write_all(&[raw_cmd::RX, len]),
@@ -129,41 +204,837 @@ impl PortSocket {
                 }
                 Ok(())
             }
+            TxGather(bufs) => {
+                // Like Tx, but one TX can span several of the slices.
+                let mut remaining: usize = bufs.iter().map(|buf| buf.len()).sum();
+                let mut bufs = bufs.iter().map(|buf| *buf).filter(|buf| !buf.is_empty());
+                let mut current: &[u8] = &[];
+                while remaining > 0 {
+                    let mut len = remaining.min(u8::max_value() as usize);
+                    try!(socket.write_all(&[raw_cmd::TX, len as u8]));
+                    remaining -= len;
+                    while len > 0 {
+                        if current.is_empty() {
+                            current = bufs.next().unwrap();
+                        }
+                        let (head, tail) = current.split_at(len.min(current.len()));
+                        try!(socket.write_all(head));
+                        len -= head.len();
+                        current = tail;
+                    }
+                }
+                Ok(())
+            }
             TxRx(data) => {
                 assert!(data.len() <= u8::max_value() as usize);
                 try!(socket.write_all(&[raw_cmd::TXRX, data.len() as u8]));
                 socket.write_all(data)
             }
+            SpiDma(data) => {
+                // One command for the whole buffer, with a 32-bit length.
+                assert!(data.len() <= u32::max_value() as usize);
+                let len = data.len() as u32;
+                try!(socket.write_all(&[raw_cmd::SPI_DMA, (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]));
+                socket.write_all(data)
+            }
             GpioIn(pin) => socket.write_all(&[raw_cmd::GPIO_IN, pin]),
             GpioHigh(pin) => socket.write_all(&[raw_cmd::GPIO_HIGH, pin]),
             GpioLow(pin) => socket.write_all(&[raw_cmd::GPIO_LOW, pin]),
             GpioToggle(pin) => socket.write_all(&[raw_cmd::GPIO_TOGGLE, pin]),
             GpioWait(pin) => socket.write_all(&[raw_cmd::GPIO_WAIT, pin]),
-            GpioInt(pin) => socket.write_all(&[raw_cmd::GPIO_INT, pin]),
-            GpioCfg(pin) => socket.write_all(&[raw_cmd::GPIO_CFG, pin]),
             GpioInput(pin) => socket.write_all(&[raw_cmd::GPIO_INPUT, pin]),
+            GpioOutput(pin) => socket.write_all(&[raw_cmd::GPIO_OUTPUT, pin]),
             GpioRawRead(pin) => socket.write_all(&[raw_cmd::GPIO_RAW_READ, pin]),
-            GpioPull(pin) => socket.write_all(&[raw_cmd::GPIO_PULL, pin]),
+            GpioReadMask(mask) => socket.write_all(&[raw_cmd::GPIO_READ_MASK, mask]),
+            GpioWriteMask{ mask, values } => socket.write_all(&[raw_cmd::GPIO_WRITE_MASK, mask, values]),
             AnalogRead(pin) => socket.write_all(&[raw_cmd::ANALOG_READ, pin]),
+            AnalogReadMulti(pins) => {
+                assert!(pins.len() <= u8::max_value() as usize);
+                try!(socket.write_all(&[raw_cmd::ANALOG_READ_MULTI, pins.len() as u8]));
+                socket.write_all(pins)
+            }
+
+            GpioPull{ pin, mode } => socket.write_all(&[raw_cmd::GPIO_PULL, mode << 4 | pin & 0x7]),
+            GpioInt{ pin, mode } => socket.write_all(&[raw_cmd::GPIO_INT, mode << 4 | pin & 0x7]),
+            GpioCfg{ pin, mode } => socket.write_all(&[raw_cmd::GPIO_CFG, mode << 4 | pin & 0x7]),
+            GpioCounterStart{ pin, mode } => socket.write_all(&[raw_cmd::GPIO_COUNTER_START, mode << 4 | pin & 0x7]),
+            GpioCounterRead(pin) => socket.write_all(&[raw_cmd::GPIO_COUNTER_READ, pin]),
+            GpioPulse{ pin, high, micros } => socket.write_all(&[raw_cmd::GPIO_PULSE, (high as u8) << 4 | pin & 0x7,
+                                                                 (micros >> 24) as u8, (micros >> 16) as u8,
+                                                                 (micros >> 8) as u8, micros as u8]),
 
             AnalogWrite{ pin, value } => socket.write_all(&[raw_cmd::ANALOG_WRITE, pin, value]),
 
             EnableSpi{ mode, freq, div } => socket.write_all(&[raw_cmd::ENABLE_SPI, mode, freq, div]),
+            SpiDataSize(bits) => socket.write_all(&[raw_cmd::SPI_DATA_SIZE, bits]),
+            SpiBitOrder(order) => socket.write_all(&[raw_cmd::SPI_BIT_ORDER, order]),
             DisableSpi => socket.write_all(&[raw_cmd::DISABLE_SPI]),
             EnableI2c{ baud } => socket.write_all(&[raw_cmd::ENABLE_I2C, baud]),
             DisableI2c => socket.write_all(&[raw_cmd::DISABLE_I2C]),
-            EnableUart{ baud, mode } => socket.write_all(&[raw_cmd::ENABLE_UART, baud, mode]),
+            I2cTimeout(ms) => socket.write_all(&[raw_cmd::I2C_TIMEOUT, (ms >> 8) as u8, (ms & 0xFF) as u8]),
+            EnableUart{ baud } => socket.write_all(&[raw_cmd::ENABLE_UART, (baud >> 8) as u8, (baud & 0xFF) as u8]),
             DisableUart => socket.write_all(&[raw_cmd::DISABLE_UART]),
+            GetVersion => socket.write_all(&[raw_cmd::GET_VERSION]),
+            Reset => socket.write_all(&[raw_cmd::RESET]),
+            Ping => socket.write_all(&[raw_cmd::PING]),
 
             Start(addr) => socket.write_all(&[raw_cmd::START, addr]),
             Stop => socket.write_all(&[raw_cmd::STOP]),
 
             PwmDutyCycle{ pin, duty_cycle } => socket.write_all(&[raw_cmd::PWM_DUTY_CYCLE, pin, (duty_cycle >> 8) as u8, (duty_cycle & 0xFF) as u8]),
-            PwmPeriod{ prescalar, tcc_id, period } => socket.write_all(&[raw_cmd::PWM_PERIOD, prescalar << 4 | tcc_id & 0x7, (period >> 8) as u8, (period & 0xf) as u8]),
+            PwmPeriod{ prescalar, tcc_id, period } => socket.write_all(&[raw_cmd::PWM_PERIOD, prescalar << 4 | tcc_id & 0x7, (period >> 8) as u8, (period & 0xFF) as u8]),
+        }
+    }
+
+    /// The command byte this is sent as.
+    pub fn byte(&self) -> u8 {
+        match *self {
+            Nop => raw_cmd::NOP,
+            Flush => raw_cmd::FLUSH,
+            GpioIn(_) => raw_cmd::GPIO_IN,
+            GpioHigh(_) => raw_cmd::GPIO_HIGH,
+            GpioLow(_) => raw_cmd::GPIO_LOW,
+            GpioToggle(_) => raw_cmd::GPIO_TOGGLE,
+            GpioWait(_) => raw_cmd::GPIO_WAIT,
+            GpioCfg{ .. } => raw_cmd::GPIO_CFG,
+            GpioInput(_) => raw_cmd::GPIO_INPUT,
+            GpioOutput(_) => raw_cmd::GPIO_OUTPUT,
+            GpioRawRead(_) => raw_cmd::GPIO_RAW_READ,
+            GpioReadMask(_) => raw_cmd::GPIO_READ_MASK,
+            GpioWriteMask{ .. } => raw_cmd::GPIO_WRITE_MASK,
+            AnalogRead(_) => raw_cmd::ANALOG_READ,
+            AnalogReadMulti(_) => raw_cmd::ANALOG_READ_MULTI,
+            GpioPull{ .. } => raw_cmd::GPIO_PULL,
+            GpioInt{ .. } => raw_cmd::GPIO_INT,
+            GpioPulse{ .. } => raw_cmd::GPIO_PULSE,
+            GpioCounterStart{ .. } => raw_cmd::GPIO_COUNTER_START,
+            GpioCounterRead(_) => raw_cmd::GPIO_COUNTER_READ,
+            AnalogWrite{ .. } => raw_cmd::ANALOG_WRITE,
+            EnableSpi{ .. } => raw_cmd::ENABLE_SPI,
+            SpiDataSize(_) => raw_cmd::SPI_DATA_SIZE,
+            SpiBitOrder(_) => raw_cmd::SPI_BIT_ORDER,
+            DisableSpi => raw_cmd::DISABLE_SPI,
+            EnableI2c{ .. } => raw_cmd::ENABLE_I2C,
+            DisableI2c => raw_cmd::DISABLE_I2C,
+            I2cTimeout(_) => raw_cmd::I2C_TIMEOUT,
+            EnableUart{ .. } => raw_cmd::ENABLE_UART,
+            DisableUart => raw_cmd::DISABLE_UART,
+            GetVersion => raw_cmd::GET_VERSION,
+            Reset => raw_cmd::RESET,
+            Ping => raw_cmd::PING,
+            Start(_) => raw_cmd::START,
+            Stop => raw_cmd::STOP,
+            PwmDutyCycle{ .. } => raw_cmd::PWM_DUTY_CYCLE,
+            PwmPeriod{ .. } => raw_cmd::PWM_PERIOD,
+            Rx(_) => raw_cmd::RX,
+            Echo(_) => raw_cmd::ECHO,
+            Tx(_) | TxGather(_) => raw_cmd::TX,
+            TxRx(_) => raw_cmd::TXRX,
+            SpiDma(_) => raw_cmd::SPI_DMA,
+        }
+    }
+
+    /// The name of the command byte, as in `raw_cmd`, for logging.
+    pub fn name(&self) -> &'static str {
+        raw_cmd::name(self.byte()).expect("Command byte is missing from raw_cmd::ALL.")
+    }
+}
+
+/// Starting byte of reply packets. Because this is extensible, we use
+/// a list of constants instead of an enum.
+pub mod reply {
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Reply(pub u8);
+
+    impl Reply {
+        /// Returns the reply starting with `byte`, or `None` if no reply
+        /// starts with it.
+        pub fn from_byte(byte: u8) -> Option<Reply> {
+            let known = (byte >= ACK.0 && byte <= PONG.0) ||
+                        (byte >= ASYNC_PIN_CHANGE_N.0 && byte < ASYNC_PIN_CHANGE_N.0 + 16) ||
+                        byte == ASYNC_UART_RX.0;
+            if known { Some(Reply(byte)) } else { None }
+        }
+
+        /// The name of the reply, as in this module, for logging.
+        pub fn name(&self) -> &'static str {
+            match self.0 {
+                x if x == ACK.0 => "ACK",
+                x if x == NACK.0 => "NACK",
+                x if x == HIGH.0 => "HIGH",
+                x if x == LOW.0 => "LOW",
+                x if x == DATA.0 => "DATA",
+                x if x == PONG.0 => "PONG",
+                x if x == ASYNC_UART_RX.0 => "ASYNC_UART_RX",
+                x if x >= ASYNC_PIN_CHANGE_N.0 && x < ASYNC_PIN_CHANGE_N.0 + 16 => "ASYNC_PIN_CHANGE_N",
+                _ => "UNKNOWN",
+            }
         }
     }
 
-    pub fn read_exact(&mut self, buffer: &mut [u8]) -> io::Result<()> {
-        self.socket.read_exact(buffer)
+    pub const ACK: Reply = Reply(0x80);
+    pub const NACK: Reply = Reply(0x81);
+    pub const HIGH: Reply = Reply(0x82);
+    pub const LOW: Reply = Reply(0x83);
+    pub const DATA: Reply = Reply(0x84);
+    pub const PONG: Reply = Reply(0x85);
+
+    pub const MIN_ASYNC: Reply = Reply(0xA0);
+    /// c0 to c8 is all async pin assignments.
+    pub const ASYNC_PIN_CHANGE_N: Reply = Reply(0xC0);
+    pub const ASYNC_UART_RX: Reply = Reply(0xD0);
+}
+
+/// A byte stream to the port daemon. Implemented for `UnixStream`; tests can
+/// implement it for an in-memory stand-in and pass it to
+/// `PortSocket::from_transport()`.
+pub trait SocketTransport: Read + Write + Send {
+    /// Sets how long reads wait before failing. Transports that never block
+    /// can ignore this.
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes reads fail with `WouldBlock` instead of waiting for data.
+    /// Transports that never block can ignore this.
+    fn set_nonblocking(&mut self, _nonblocking: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the file descriptor to wait on for incoming data, if the
+    /// transport has one.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl SocketTransport for UnixStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// Socket that communicates with the SAMD21.
+///
+/// # Reply framing
+///
+/// The coprocessor answers commands in the order they were sent. Every reply
+/// starts with one of the `reply` bytes:
+///
+/// * `ACK`, `NACK`, `HIGH`, `LOW`, and `PONG` (the answer to `Ping`) are a
+///   single byte.
+/// * `DATA` is followed by exactly as many bytes as the command asked for
+///   (e.g. the length of an `Rx`, two bytes for an `AnalogRead` and two per
+///   pin for an `AnalogReadMulti`, or one byte for a `GpioReadMask`, with
+///   bit `n` holding the level of pin `n`).
+/// * An I2C write, from `Start` to `Stop` with no `Rx` between them, is
+///   answered with `ACK` if the slave acknowledged its address, or `NACK` if
+///   it didn't.
+/// * `SpiDma` is answered with `ACK` once the whole buffer has been clocked
+///   out.
+/// * An I2C `Rx` is answered with `NACK` instead of `DATA` if the slave
+///   stopped acknowledging before it was reached, e.g. a device with nothing
+///   more to send; the following `Rx` commands of that read are too.
+///
+/// Asynchronous frames, with a first byte of at least `MIN_ASYNC`, may arrive
+/// between any two replies:
+///
+/// * `ASYNC_PIN_CHANGE_N + n`, for `n` below 16, reports that pin `n & 0x7`
+///   changed; bit 3 of `n` holds the pin's new level.
+/// * `ASYNC_UART_RX` is followed by a length byte and that many bytes of
+///   received UART data.
+/// * Any other byte of at least `MIN_ASYNC` is a frame this crate doesn't
+///   know, e.g. from newer firmware. Only its first byte is skipped, so one
+///   with a body desyncs the stream like any other stray data.
+///
+/// The `read_*` methods below set asynchronous frames aside as they are
+/// encountered, so a caller waiting on its reply never sees another
+/// peripheral's data.
+///
+/// # Tracing
+///
+/// With the `log` feature enabled, every command written and every read from
+/// the socket is logged at trace level with its size, which helps when
+/// reporting hardware issues.
+pub struct PortSocket {
+    // Where to reconnect to, if the socket was opened by path.
+    socket_path: Option<PathBuf>,
+    socket: Box<dyn SocketTransport>,
+    // Pin changes received but not yet taken, as (pin, level).
+    pin_changes: VecDeque<(u8, bool)>,
+    // UART data received but not yet read.
+    uart_rx: VecDeque<u8>,
+    // How long reads wait before failing with `TimedOut`.
+    read_timeout: Option<Duration>,
+    // Clock characteristics of the coprocessor on the other end.
+    profile: McuProfile,
+    // Commands written inside batch(), not yet sent.
+    batch: Option<Vec<u8>>,
+    // The most bytes handed to the socket in one write.
+    chunk_size: usize,
+    // Bytes the socket has accepted so far, in whole chunks.
+    bytes_sent: u64,
+}
+
+impl fmt::Debug for PortSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PortSocket")
+            .field("socket_path", &self.socket_path)
+            .field("pin_changes", &self.pin_changes)
+            .field("uart_rx", &self.uart_rx.len())
+            .finish()
+    }
+}
+
+impl PortSocket {
+    pub fn new<P: AsRef<Path>>(path: P) -> PortSocket {
+        PortSocket::try_new(path).expect("Could not connect to port socket.")
+    }
+
+    pub fn try_new<P: AsRef<Path>>(path: P) -> Result<PortSocket, TesselError> {
+        // Connect to the unix domain socket for this port
+        let socket = try!(UnixStream::connect(path.as_ref()));
+
+        Ok(PortSocket::new_with_path(path.as_ref().to_path_buf(), socket))
+    }
+
+    /// Wraps an already connected stream, such as one end of a
+    /// `UnixStream::pair()` standing in for the port daemon.
+    pub fn from_stream(socket: UnixStream) -> PortSocket {
+        PortSocket::from_transport(Box::new(socket))
+    }
+
+    /// Wraps any transport, such as a mock that records written commands.
+    pub fn from_transport(transport: Box<dyn SocketTransport>) -> PortSocket {
+        PortSocket {
+            socket_path: None,
+            socket: transport,
+            pin_changes: VecDeque::new(),
+            uart_rx: VecDeque::new(),
+            read_timeout: None,
+            profile: McuProfile::tessel2(),
+            batch: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            bytes_sent: 0,
+        }
+    }
+
+    fn new_with_path(path: PathBuf, socket: UnixStream) -> PortSocket {
+        PortSocket {
+            socket_path: Some(path),
+            socket: Box::new(socket),
+            pin_changes: VecDeque::new(),
+            uart_rx: VecDeque::new(),
+            read_timeout: None,
+            profile: McuProfile::tessel2(),
+            batch: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            bytes_sent: 0,
+        }
+    }
+
+    /// Returns the file descriptor of the socket, or `None` if it wraps a
+    /// transport without one.
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.socket.raw_fd()
+    }
+
+    /// Returns the path the socket was opened from, or `None` if it wraps a
+    /// stream or transport.
+    pub fn path(&self) -> Option<&Path> {
+        self.socket_path.as_ref().map(|path| path.as_path())
+    }
+
+    /// Returns the clock characteristics of the coprocessor, used to compute
+    /// baud and period registers.
+    pub fn profile(&self) -> McuProfile {
+        self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: McuProfile) {
+        self.profile = profile;
+    }
+
+    /// Returns the most bytes sent to the daemon in one write.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Sets the most bytes sent to the daemon in one write (256 by default).
+    /// Longer writes, such as a stream of UART or SPI data, are split into
+    /// chunks of this size, yielding to other threads between them so the
+    /// daemon can keep up.
+    ///
+    /// # Panics
+    ///
+    /// If `chunk_size` is zero.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        assert!(chunk_size > 0, "Chunk size must be at least one byte.");
+        self.chunk_size = chunk_size;
+    }
+
+    /// Replaces the socket with a new connection to the same path, e.g.
+    /// after the port daemon restarted.
+    pub fn reconnect(&mut self) -> Result<(), TesselError> {
+        let socket = match self.socket_path {
+            Some(ref path) => try!(UnixStream::connect(path)),
+            None => return Err(TesselError::Io(io::Error::new(io::ErrorKind::NotConnected, "Socket has no path to reconnect to."))),
+        };
+        try!(socket.set_read_timeout(self.read_timeout));
+        self.socket = Box::new(socket);
+        Ok(())
+    }
+
+    pub fn raw_write(&mut self, buffer: &[u8]) -> Result<(), TesselError> {
+        if let Some(ref mut batch) = self.batch {
+            batch.extend_from_slice(buffer);
+            return Ok(());
+        }
+        self.write_socket(buffer)
+    }
+
+    /// Runs `f`, collecting every command it writes and sending them all in
+    /// one write when it returns, instead of one write per command. This
+    /// saves a round trip through the kernel for each command of a short
+    /// transaction, such as the START, TX and STOP of an I2C write.
+    ///
+    /// Replies to the commands can't arrive until they are sent, so `f`
+    /// should only write. If `f` fails, nothing is sent. Nested batches
+    /// join the outer one.
+    pub fn batch<T, F>(&mut self, f: F) -> Result<T, TesselError>
+        where F: FnOnce(&mut PortSocket) -> Result<T, TesselError>
+    {
+        if self.batch.is_some() {
+            return f(self);
+        }
+
+        self.batch = Some(vec![]);
+        let result = f(self);
+        let buffer = self.batch.take().unwrap();
+        let value = try!(result);
+        if !buffer.is_empty() {
+            try!(self.write_socket(&buffer));
+        }
+        Ok(value)
+    }
+
+    fn write_socket(&mut self, buffer: &[u8]) -> Result<(), TesselError> {
+        match self.write_chunks(buffer) {
+            Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe ||
+                            err.kind() == io::ErrorKind::ConnectionReset => {
+                // The daemon may have restarted; reconnect once and send the
+                // whole buffer again, since the new daemon saw none of it.
+                try!(self.reconnect());
+                Ok(try!(self.write_chunks(buffer)))
+            }
+            result => Ok(try!(result)),
+        }
+    }
+
+    fn write_chunks(&mut self, buffer: &[u8]) -> io::Result<()> {
+        for (i, chunk) in buffer.chunks(self.chunk_size).enumerate() {
+            if i > 0 {
+                thread::yield_now();
+            }
+            try!(self.socket.write_all(chunk));
+            self.bytes_sent += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes the daemon has accepted over this socket, e.g.
+    /// to tell whether a failed write sent anything at all. A chunk that
+    /// failed partway isn't counted.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Sends a raw command byte and its arguments, and collects the reply.
+    /// This is an escape hatch for commands `Command` doesn't cover yet.
+    ///
+    /// `reply_len` is `None` for commands the coprocessor doesn't answer, or
+    /// the number of data bytes that follow a `DATA` reply (zero for
+    /// commands answered with a single byte, such as `ACK` or `HIGH`).
+    /// Returns the reply byte followed by any data.
+    pub fn command(&mut self, cmd: u8, args: &[u8], reply_len: Option<usize>) -> Result<Vec<u8>, TesselError> {
+        let mut buffer = vec![cmd];
+        buffer.extend_from_slice(args);
+        try!(self.raw_write(&buffer));
+
+        let reply_len = match reply_len {
+            Some(len) => len,
+            None => return Ok(vec![]),
+        };
+        let header = try!(self.read_reply());
+        let mut reply = vec![header];
+        if header == reply::DATA.0 {
+            reply.resize(reply_len + 1, 0);
+            try!(self.read_socket(&mut reply[1..]));
+        }
+        Ok(reply)
+    }
+
+    pub fn write_command(&mut self, cmd: Command) -> Result<(), TesselError> {
+        // Encode the whole command first, so it can be resent after a reconnect.
+        let buffer = cmd.encode();
+        trace_traffic!("write {} ({} bytes)", cmd.name(), buffer.len());
+        self.raw_write(&buffer)
+    }
+
+    pub fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), TesselError> {
+        self.read_socket(buffer)
+    }
+
+    /// Sets how long reads wait for the coprocessor before failing with
+    /// `TimedOut`. `None` waits forever.
+    ///
+    /// A read that times out partway through a reply leaves the rest of the
+    /// reply unread, so the socket should be reconnected afterwards.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), TesselError> {
+        try!(self.socket.set_read_timeout(timeout));
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Runs `f` with reads timing out after `timeout`, then restores the
+    /// previous timeout. `None` keeps the current timeout.
+    pub fn with_read_timeout<T, F>(&mut self, timeout: Option<Duration>, f: F) -> Result<T, TesselError>
+        where F: FnOnce(&mut PortSocket) -> Result<T, TesselError>
+    {
+        if timeout.is_none() {
+            return f(self);
+        }
+
+        let previous = self.read_timeout;
+        try!(self.set_read_timeout(timeout));
+        let result = f(self);
+        try!(self.set_read_timeout(previous));
+        result
+    }
+
+    /// Discards any bytes already waiting on the socket without blocking,
+    /// such as the rest of a reply left behind by an aborted transfer.
+    /// Asynchronous frames among them are lost too.
+    pub fn drain(&mut self) -> Result<(), TesselError> {
+        try!(self.socket.set_nonblocking(true));
+        let mut buffer = [0; 256];
+        let result = loop {
+            match self.socket.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => break Err(err),
+            }
+        };
+        try!(self.socket.set_nonblocking(false));
+        Ok(try!(result))
+    }
+
+    /// Reads the next synchronous reply byte, setting aside any asynchronous
+    /// frames that arrive first.
+    ///
+    /// Replies carry no length or checksum, so the only sign of a desync
+    /// (e.g. after a transfer was abandoned partway) is a header that isn't
+    /// a reply byte. Then the rest of the socket is drained so the next
+    /// command starts clean, and `InvalidReply` is returned.
+    pub fn read_reply(&mut self) -> Result<u8, TesselError> {
+        loop {
+            let header = try!(self.read_byte());
+            if header >= reply::MIN_ASYNC.0 {
+                try!(self.read_async(header));
+            } else if header >= reply::ACK.0 && header <= reply::PONG.0 {
+                return Ok(header);
+            } else {
+                try!(self.drain());
+                return Err(TesselError::InvalidReply);
+            }
+        }
+    }
+
+    /// Reads a `DATA` reply into `buffer`, which must be exactly as long as
+    /// the data the command asked for.
+    pub fn read_data(&mut self, buffer: &mut [u8]) -> Result<(), TesselError> {
+        if try!(self.read_reply()) != reply::DATA.0 {
+            return Err(TesselError::InvalidReply);
+        }
+        self.read_socket(buffer)
+    }
+
+    /// Like `read_data()`, but returns `false` instead of failing if the
+    /// coprocessor answered with `NACK`.
+    pub fn read_data_or_nack(&mut self, buffer: &mut [u8]) -> Result<bool, TesselError> {
+        match try!(self.read_reply()) {
+            x if x == reply::DATA.0 => {
+                try!(self.read_socket(buffer));
+                Ok(true)
+            }
+            x if x == reply::NACK.0 => Ok(false),
+            _ => Err(TesselError::InvalidReply),
+        }
+    }
+
+    /// Reads received UART data into `buffer`, blocking until at least one
+    /// byte is available. Returns the number of bytes read.
+    pub fn read_uart(&mut self, buffer: &mut [u8]) -> Result<usize, TesselError> {
+        while self.uart_rx.is_empty() {
+            let header = try!(self.read_byte());
+            if header < reply::MIN_ASYNC.0 {
+                return Err(TesselError::InvalidReply);
+            }
+            try!(self.read_async(header));
+        }
+
+        let len = buffer.len().min(self.uart_rx.len());
+        for (dest, byte) in buffer.iter_mut().zip(self.uart_rx.drain(..len)) {
+            *dest = byte;
+        }
+        Ok(len)
+    }
+
+    /// Takes the oldest pin change received so far, as (pin, level).
+    pub fn take_pin_change(&mut self) -> Option<(u8, bool)> {
+        self.pin_changes.pop_front()
+    }
+
+    /// Blocks until `pin` changes, returning its new level. Changes to other
+    /// pins are kept for `take_pin_change()`.
+    pub fn wait_pin_change(&mut self, pin: u8) -> Result<bool, TesselError> {
+        loop {
+            if let Some(i) = self.pin_changes.iter().position(|&(p, _)| p == pin) {
+                return Ok(self.pin_changes.remove(i).unwrap().1);
+            }
+
+            let header = try!(self.read_byte());
+            if header < reply::MIN_ASYNC.0 {
+                return Err(TesselError::InvalidReply);
+            }
+            try!(self.read_async(header));
+        }
+    }
+
+    /// Forgets any changes to `pin` received so far.
+    pub fn discard_pin_changes(&mut self, pin: u8) {
+        self.pin_changes.retain(|&(p, _)| p != pin);
+    }
+
+    fn read_socket(&mut self, buffer: &mut [u8]) -> Result<(), TesselError> {
+        trace_traffic!("read {} bytes", buffer.len());
+        self.socket.read_exact(buffer).map_err(|err| match err.kind() {
+            // Which of these a timeout produces depends on the platform.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => TesselError::TimedOut,
+            _ => TesselError::Io(err),
+        })
+    }
+
+    fn read_byte(&mut self) -> Result<u8, TesselError> {
+        let mut byte = [0];
+        try!(self.read_socket(&mut byte));
+        Ok(byte[0])
+    }
+
+    // Reads the rest of the asynchronous frame starting with `header`.
+    fn read_async(&mut self, header: u8) -> Result<(), TesselError> {
+        if header >= reply::ASYNC_PIN_CHANGE_N.0 && header < reply::ASYNC_PIN_CHANGE_N.0 + 16 {
+            let n = header - reply::ASYNC_PIN_CHANGE_N.0;
+            self.pin_changes.push_back((n & 0x7, n & 0x8 != 0));
+        } else if header == reply::ASYNC_UART_RX.0 {
+            let mut data = vec![0; try!(self.read_byte()) as usize];
+            try!(self.read_socket(&mut data));
+            self.uart_rx.extend(data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use std::io::{Read, Write};
+    use unix_socket::{UnixListener, UnixStream};
+
+    #[test]
+    fn raw_commands_collect_replies() {
+        let (client, mut daemon) = UnixStream::pair().unwrap();
+        let mut socket = PortSocket::from_stream(client);
+        daemon.write_all(&[0x82, 0x84, 0xAB, 0xCD]).unwrap();
+
+        assert_eq!(vec![0x82], socket.command(raw_cmd::GPIO_IN, &[3], Some(0)).unwrap());
+        assert_eq!(vec![0x84, 0xAB, 0xCD], socket.command(raw_cmd::ANALOG_READ, &[4], Some(2)).unwrap());
+        assert_eq!(Vec::<u8>::new(), socket.command(raw_cmd::GPIO_HIGH, &[5], None).unwrap());
+        drop(socket);
+
+        let mut written = vec![];
+        daemon.read_to_end(&mut written).unwrap();
+        assert_eq!(vec![0x03, 3, 0x18, 4, 0x04, 5], written);
+    }
+
+    // Records each write call separately, to count them.
+    struct WriteLog(::std::sync::Arc<::std::sync::Mutex<Vec<Vec<u8>>>>);
+
+    impl Read for WriteLog {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for WriteLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SocketTransport for WriteLog {}
+
+    #[test]
+    fn batches_send_commands_in_one_write() {
+        let writes = ::std::sync::Arc::new(::std::sync::Mutex::new(vec![]));
+        let mut socket = PortSocket::from_transport(Box::new(WriteLog(writes.clone())));
+
+        socket.batch(|socket| {
+            try!(socket.write_command(Command::Start(0x50)));
+            try!(socket.batch(|socket| socket.write_command(Command::Tx(&[1, 2]))));
+            socket.write_command(Command::Stop)
+        }).unwrap();
+        assert_eq!(vec![vec![0x13, 0x50, 0x10, 2, 1, 2, 0x14]], *writes.lock().unwrap());
+
+        // A failed batch sends nothing.
+        let result: Result<(), _> = socket.batch(|socket| {
+            try!(socket.write_command(Command::Start(0x50)));
+            Err(TesselError::Nack)
+        });
+        assert!(matches!(result, Err(TesselError::Nack)));
+        socket.write_command(Command::Stop).unwrap();
+        assert_eq!(vec![0x14], writes.lock().unwrap()[1]);
+        assert_eq!(2, writes.lock().unwrap().len());
+    }
+
+    #[test]
+    fn long_writes_are_split_into_chunks() {
+        let writes = ::std::sync::Arc::new(::std::sync::Mutex::new(vec![]));
+        let mut socket = PortSocket::from_transport(Box::new(WriteLog(writes.clone())));
+        assert_eq!(256, socket.chunk_size());
+
+        socket.set_chunk_size(4);
+        socket.write_command(Command::Tx(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10])).unwrap();
+        socket.write_command(Command::Stop).unwrap();
+        assert_eq!(vec![vec![0x10, 10, 1, 2], vec![3, 4, 5, 6], vec![7, 8, 9, 10], vec![0x14]],
+                   *writes.lock().unwrap());
+    }
+
+    #[test]
+    fn writes_reconnect_after_broken_pipe() {
+        let path = env::temp_dir().join(format!("tessel-reconnect-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut socket = PortSocket::try_new(&path).unwrap();
+
+        // The daemon restarts, closing the first connection.
+        drop(listener.accept().unwrap().0);
+        socket.write_command(Command::GpioHigh(3)).unwrap();
+
+        let (mut daemon, _) = listener.accept().unwrap();
+        drop(socket);
+        let mut written = vec![];
+        daemon.read_to_end(&mut written).unwrap();
+        assert_eq!(vec![0x04, 3], written);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replies_skip_async_frames() {
+        let (client, mut daemon) = UnixStream::pair().unwrap();
+        let mut socket = PortSocket::from_stream(client);
+        daemon.write_all(&[0xD0, 2, b'h', b'i', 0xCD, 0x84, 0x12, 0x34]).unwrap();
+
+        // The DATA reply is found behind the UART and pin change frames.
+        let mut data = [0; 2];
+        socket.read_data(&mut data).unwrap();
+        assert_eq!([0x12, 0x34], data);
+
+        assert_eq!(Some((5, true)), socket.take_pin_change());
+        assert_eq!(None, socket.take_pin_change());
+        let mut buf = [0; 8];
+        assert_eq!(2, socket.read_uart(&mut buf).unwrap());
+        assert_eq!(b"hi", &buf[..2]);
+    }
+
+    #[test]
+    fn replies_skip_unknown_async_frames() {
+        let (client, mut daemon) = UnixStream::pair().unwrap();
+        let mut socket = PortSocket::from_stream(client);
+        daemon.write_all(&[0xA3, 0x80, 0xE0, 0x82, 0xB1]).unwrap();
+
+        assert_eq!(reply::ACK.0, socket.read_reply().unwrap());
+        assert_eq!(reply::HIGH.0, socket.read_reply().unwrap());
+        // Waiting on a pin change skips them too.
+        daemon.write_all(&[0xC2]).unwrap();
+        assert_eq!(false, socket.wait_pin_change(2).unwrap());
+    }
+
+    #[test]
+    fn desynced_replies_are_rejected_and_drained() {
+        let (client, mut daemon) = UnixStream::pair().unwrap();
+        let mut socket = PortSocket::from_stream(client);
+        // The tail of an earlier DATA reply, then more of it.
+        daemon.write_all(&[0x12, 0x34, 0x56]).unwrap();
+
+        let mut data = [0; 1];
+        assert!(matches!(socket.read_data(&mut data), Err(TesselError::InvalidReply)));
+
+        daemon.write_all(&[0x84, 0x78]).unwrap();
+        socket.read_data(&mut data).unwrap();
+        assert_eq!([0x78], data);
+    }
+
+    #[test]
+    fn commands_and_replies_describe_themselves() {
+        // Every command byte is listed once.
+        for (i, &(byte, name)) in raw_cmd::ALL.iter().enumerate() {
+            assert_eq!(Some(name), raw_cmd::name(byte));
+            assert!(raw_cmd::ALL[..i].iter().all(|&(other, _)| other != byte));
+        }
+        assert_eq!("I2C_TIMEOUT", Command::I2cTimeout(10).name());
+        assert_eq!("TX", Command::Tx(&[1, 2]).name());
+        assert_eq!(None, raw_cmd::name(0xFF));
+
+        assert_eq!(Some(reply::DATA), reply::Reply::from_byte(0x84));
+        assert_eq!("ASYNC_PIN_CHANGE_N", reply::Reply::from_byte(0xCD).unwrap().name());
+        assert_eq!(None, reply::Reply::from_byte(0x12));
+        assert_eq!(None, reply::Reply::from_byte(reply::MIN_ASYNC.0));
+    }
+
+    #[test]
+    fn drain_discards_pending_bytes() {
+        let (client, mut daemon) = UnixStream::pair().unwrap();
+        let mut socket = PortSocket::from_stream(client);
+        daemon.write_all(&[0x84, 0xFF, 0xFF]).unwrap();
+
+        socket.drain().unwrap();
+        // Draining an empty socket doesn't block.
+        socket.drain().unwrap();
+
+        daemon.write_all(&[0x84, 0x12]).unwrap();
+        let mut data = [0; 1];
+        socket.read_data(&mut data).unwrap();
+        assert_eq!([0x12], data);
     }
 }